@@ -0,0 +1,70 @@
+//! Benchmarks for the hot paths on fixed, seeded inputs, so numbers are comparable across
+//! runs and branches. Run with `cargo bench`.
+//!
+//! The search itself lives in the cdx_worker binary, so `worker_work` times a whole worker
+//! process answering Init + Work over a synthetic category set.
+
+use cdx_helper::adjacency::build;
+use cdx_helper::bitset::{intersects, subset};
+use cdx_helper::rng::SplitMix64;
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const WORDS: usize = 1024;
+const LIMBS: usize = WORDS / 32;
+const CLUSTERS: usize = 16;
+
+// `n` categories of 8 to 23 words. Each draws from one of CLUSTERS overlapping windows of
+// 128 words, so intersections and shared neighbours are common, as in the real word list.
+fn synthetic_masks(n: usize, seed: u64) -> Vec<Vec<u32>> {
+    let mut rng = SplitMix64::new(seed);
+    (0..n).map(|_| {
+        let base = rng.below(CLUSTERS) * (WORDS - 128) / (CLUSTERS - 1);
+        let mut m = vec![0u32; LIMBS];
+        for _ in 0..8 + rng.below(16) {
+            let w = base + rng.below(128);
+            m[w / 32] |= 1 << (w % 32);
+        }
+        m
+    }).collect()
+}
+
+fn bitset_predicates(c: &mut Criterion) {
+    let masks = synthetic_masks(256, 1);
+    c.bench_function("intersects_all_pairs", |b| b.iter(|| {
+        masks.iter().map(|x| masks.iter().filter(|y| intersects(black_box(x), y)).count()).sum::<usize>()
+    }));
+    c.bench_function("subset_all_pairs", |b| b.iter(|| {
+        masks.iter().map(|x| masks.iter().filter(|y| subset(black_box(x), y)).count()).sum::<usize>()
+    }));
+}
+
+fn adjacency_build(c: &mut Criterion) {
+    let masks = synthetic_masks(300, 2);
+    c.bench_function("adjacency_build_300", |b| b.iter(|| build(black_box(&masks), false, 0)));
+}
+
+fn worker_work(c: &mut Criterion) {
+    let n = 120;
+    let init = serde_json::json!({
+        "type": "Init",
+        "masks": synthetic_masks(n, 3),
+        "categories": (0..n).map(|i| format!("c{}", i)).collect::<Vec<String>>(),
+        "meta_map": vec![serde_json::Value::Null; n],
+    });
+    let input = format!("{}\n{}\n", init, r#"{"type":"Work","start":0,"end":10}"#);
+    let mut group = c.benchmark_group("worker");
+    group.sample_size(10);
+    group.bench_function("work_120_categories_i_0_10", |b| b.iter(|| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_cdx_worker"))
+            .stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+        child.wait_with_output().unwrap().stdout.len()
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, bitset_predicates, adjacency_build, worker_work);
+criterion_main!(benches);
@@ -0,0 +1,159 @@
+//! Building and incrementally maintaining the n1/n2 adjacency lists (sorted ascending).
+//!
+//! n1 joins intersecting categories, n2 joins
+//! pairs with at least 4 common n1 neighbours, and neither joins subset-related pairs unless
+//! `allow_subset` is set. A non-zero `min_shared` also requires an n2 pair to have at least
+//! that many words in common.
+
+use crate::bitset::{intersects, shared_words, subset, subset_matrix};
+use std::collections::HashSet;
+
+/// Full n1/n2 build from scratch (what cdx_helper prints as N1/N2).
+pub fn build(masks: &[Vec<u32>], allow_subset: bool, min_shared: u32) -> (Vec<Vec<usize>>, Vec<Vec<usize>>) {
+    let mut n2 = Vec::with_capacity(masks.len());
+    let n1 = build_each(masks, allow_subset, min_shared, |_, _, row| n2.push(row));
+    (n1, n2)
+}
+
+/// Like `build`, but hands over each category's (i, n1[i], n2[i]) in ascending i as soon as
+/// n2[i] is final, so callers can stream rows instead of holding every n2 list. Returns n1.
+#[allow(non_snake_case)]
+pub fn build_each<F: FnMut(usize, &[usize], Vec<usize>)>(masks: &[Vec<u32>], allow_subset: bool, min_shared: u32, mut f: F) -> Vec<Vec<usize>> {
+    let n = masks.len();
+    let mask_slices: Vec<&[u32]> = masks.iter().map(|v| v.as_slice()).collect();
+
+    // S(i,j) is subset relation; we only need it to exclude relations in N1/N2 like in JS
+    // Compute subset matrix (symmetric: either mask contains the other), skipped when allowed
+    let subset = (!allow_subset).then(|| subset_matrix(masks, true));
+    let related = |i: usize, j: usize| subset.as_ref().is_some_and(|s| s.get(i, j));
+
+    // Build A (1-away) and collect N1 sets
+    let mut A: Vec<Vec<u8>> = vec![vec![0; n]; n];
+    let mut n1: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for i in 0..n {
+        for j in (i + 1)..n {
+            if !related(i, j) && intersects(mask_slices[i], mask_slices[j]) {
+                A[i][j] = 1;
+                A[j][i] = 1;
+                n1[i].push(j);
+                n1[j].push(i);
+            }
+        }
+    }
+
+    // Compute A2 = A * A (boolean count)
+    // and build B with threshold >= 4 (and not subset)
+    let mut n2: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for i in 0..n {
+        for j in (i + 1)..n {
+            if related(i, j) { continue; }
+            // Count k where A[i][k] == 1 and A[k][j] == 1
+            let count = (0..n).filter(|&k| A[i][k] == 1 && A[k][j] == 1).count();
+            if count >= 4 && shares_enough(&masks[i], &masks[j], min_shared) {
+                n2[i].push(j);
+                n2[j].push(i);
+            }
+        }
+        // pairs with smaller i were pushed in earlier rounds, so row i is complete
+        f(i, &n1[i], std::mem::take(&mut n2[i]));
+    }
+
+    n1
+}
+
+/// The `min_shared` half of the n2 rule (always true when it is 0).
+pub fn shares_enough(a: &[u32], b: &[u32], min_shared: u32) -> bool {
+    min_shared == 0 || shared_words(a, b) >= min_shared
+}
+
+/// Number of entries two sorted adjacency lists have in common.
+pub fn count_common(a: &[usize], b: &[usize]) -> usize {
+    let (mut x, mut y, mut n) = (0usize, 0usize, 0usize);
+    while x < a.len() && y < b.len() {
+        if a[x] == b[y] { n += 1; x += 1; y += 1; } else if a[x] < b[y] { x += 1; } else { y += 1; }
+    }
+    n
+}
+
+pub fn insert_sorted(v: &mut Vec<usize>, x: usize) {
+    if let Err(pos) = v.binary_search(&x) { v.insert(pos, x); }
+}
+
+fn remove_sorted(v: &mut Vec<usize>, x: usize) {
+    if let Ok(pos) = v.binary_search(&x) { v.remove(pos); }
+}
+
+/// Recomputes every edge touching the `changed` indices after their masks were edited in
+/// place. n2 is rechecked for pairs involving a changed index and for pairs that neighbour
+/// one (before or after the edit), since those are the only shared-neighbour counts that move.
+pub fn patch_changed(masks: &[Vec<u32>], n1: &mut [Vec<usize>], n2: &mut [Vec<usize>], changed: &[usize], allow_subset: bool, min_shared: u32) {
+    let n = masks.len();
+    let related = |a: usize, b: usize| !allow_subset && (subset(&masks[a], &masks[b]) || subset(&masks[b], &masks[a]));
+
+    let mut nb: HashSet<usize> = HashSet::new(); // n1 neighbours of a changed index, old or new
+    for &c in changed {
+        for y in std::mem::take(&mut n1[c]) {
+            remove_sorted(&mut n1[y], c);
+            nb.insert(y);
+        }
+    }
+    for &c in changed {
+        for y in 0..n {
+            if y != c && !related(c, y) && intersects(&masks[c], &masks[y]) {
+                insert_sorted(&mut n1[c], y);
+                insert_sorted(&mut n1[y], c);
+                nb.insert(y);
+            }
+        }
+    }
+
+    let mut pairs: HashSet<(usize, usize)> = HashSet::new();
+    for &c in changed {
+        for y in 0..n { if y != c { pairs.insert((c.min(y), c.max(y))); } }
+    }
+    let nb: Vec<usize> = nb.into_iter().collect();
+    for (a, &p) in nb.iter().enumerate() {
+        for &q in &nb[(a + 1)..] { pairs.insert((p.min(q), p.max(q))); }
+    }
+    for (a, b) in pairs {
+        if !related(a, b) && count_common(&n1[a], &n1[b]) >= 4 && shares_enough(&masks[a], &masks[b], min_shared) {
+            insert_sorted(&mut n2[a], b);
+            insert_sorted(&mut n2[b], a);
+        } else {
+            remove_sorted(&mut n2[a], b);
+            remove_sorted(&mut n2[b], a);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two rows sharing one word and two columns sharing two, each crossing all four of
+    // the others' words: (0, 1) and (2, 3) are n2 pairs with 1 and 2 words in common.
+    fn masks() -> Vec<Vec<u32>> {
+        vec![vec![0b0000_1111], vec![0b1111_0001], vec![0b0001_0011], vec![0b0011_0011], vec![0b0110_0110], vec![0b1100_1100]]
+    }
+
+    #[test]
+    fn min_shared_drops_weak_n2_edges() {
+        let (n1, n2) = build(&masks(), true, 0);
+        assert_eq!(build(&masks(), true, 1), (n1.clone(), n2.clone()));
+        let (k1, k2) = build(&masks(), true, 2);
+        assert_eq!(k1, n1);
+        assert!(k2.iter().flatten().count() < n2.iter().flatten().count());
+        for (i, row) in k2.iter().enumerate() {
+            for &j in row { assert!(shared_words(&masks()[i], &masks()[j]) >= 2); }
+        }
+    }
+
+    #[test]
+    fn patch_matches_build_with_min_shared() {
+        let mut m = masks();
+        let (mut n1, mut n2) = build(&m, true, 2);
+        m[3] = vec![0b0000_0011];
+        patch_changed(&m, &mut n1, &mut n2, &[3], true, 2);
+        assert_eq!((n1, n2), build(&m, true, 2));
+    }
+}
@@ -1,31 +1,85 @@
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use std::io::{BufRead, BufReader, Write};
+use cdx_helper::meta::MetaTags;
+use cdx_helper::wire::{self, Encoding};
+use std::io::BufReader;
 
 #[derive(Deserialize)]
 #[serde(tag = "type")]
+#[allow(non_snake_case)]
 enum Msg {
     Init {
         categories: HashMap<String, Vec<String>>, // category -> words
-        meta_map: HashMap<String, String>,        // category -> meta
+        meta_map: HashMap<String, MetaTags>,      // category -> meta (or list of metas)
+        maxShare: Option<u32>,                    // a cell word may appear in up to this many other categories
+        uniqueSolution: Option<bool>,             // note cells that have more than one candidate word
+        softMeta: Option<bool>,                   // report meta overflow as a note instead of Invalid
+        distinctMeta: Option<bool>,               // cap every meta at one category per grid
     },
     Validate {
         rows: [String; 4],
         cols: [String; 4],
     },
+    CellCounts {
+        rows: [String; 4],
+        cols: [String; 4],
+    },
+    CountSolutions {
+        rows: [String; 4],
+        cols: [String; 4],
+    },
+    EnumerateSolutions {
+        rows: [String; 4],
+        cols: [String; 4],
+        limit: usize, // stop after this many Solution messages
+    },
+    FindNearDuplicates {
+        jaccard: f64, // report pairs with similarity >= this
+    },
+    ValidateStored {
+        items: Vec<([String; 4], [String; 4], String)>, // (rows, cols, puzzle_hash)
+    },
+    Rescore {
+        items: Vec<(String, [String; 4], [String; 4])>, // (puzzle_hash, rows, cols)
+    },
+    Ping, // liveness probe: answered with Pong, works before Init too
 }
 
 #[derive(Serialize)]
 #[serde(tag = "type")]
+#[allow(clippy::large_enum_variant)] // built and written one at a time
 enum Out {
     Ready,
-    Valid,
+    Valid {
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        notes: Vec<String>, // warnings from uniqueSolution / softMeta
+    },
     Invalid { reason: String },
+    Failed { hash: String, reason: String },
+    Done { checked: usize, failed: usize },
+    Pairs { pairs: Vec<(String, String, f64)> },
+    Scores { items: Vec<(String, f64)> }, // valid Rescore items in input order; invalid ones get a Failed first
+    CellCounts {
+        counts: [[usize; 4]; 4], // usable words per cell, 0 where none survive
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>, // set when the grid is invalid
+    },
+    SolutionCount {
+        count: u64, // ways to give every cell a distinct candidate word
+        many: bool, // count stopped at SOLUTION_CAP, or the search ran out of FILLING_STEPS
+    },
+    Solution { cells: [[String; 4]; 4] },
+    SolutionsDone { emitted: usize },
+    Pong,
 }
 
 struct State {
     cats: HashMap<String, HashSet<String>>, // category -> word set
-    meta: HashMap<String, String>,
+    meta: HashMap<String, Vec<String>>,
+    max_share: u32,
+    unique_solution: bool,
+    soft_meta: bool,
+    distinct_meta: bool,
 }
 
 fn intersect(a: &HashSet<String>, b: &HashSet<String>) -> HashSet<String> {
@@ -36,13 +90,18 @@ fn intersect(a: &HashSet<String>, b: &HashSet<String>) -> HashSet<String> {
     }
 }
 
+// How many of a grid's categories may share meta `m`.
+fn meta_cap(m: &str, state: &State) -> usize {
+    if state.distinct_meta || m == "Letter Patterns" { 1 } else { 2 }
+}
+
 fn check_meta(rows: &[String;4], cols: &[String;4], state: &State) -> Result<(), String> {
     let mut counts: HashMap<&str, usize> = HashMap::new();
     for c in rows.iter().chain(cols.iter()) {
-        if let Some(m) = state.meta.get(c) {
+        for m in state.meta.get(c).into_iter().flatten() {
             let e = counts.entry(m.as_str()).or_insert(0);
             *e += 1;
-            let max_allowed = if m == "Letter Patterns" { 1 } else { 2 };
+            let max_allowed = meta_cap(m, state);
             if *e > max_allowed { 
                 return Err(format!("Meta-category constraint violated: \"{}\" appears {} times (max {} allowed)", m, *e, max_allowed)); 
             }
@@ -51,61 +110,406 @@ fn check_meta(rows: &[String;4], cols: &[String;4], state: &State) -> Result<(),
     Ok(())
 }
 
-fn validate(rows: [String;4], cols: [String;4], state: &State) -> Result<(), String> {
+// softMeta: one warning per meta over its cap, in name order.
+fn meta_warnings(rows: &[String;4], cols: &[String;4], state: &State) -> Vec<String> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for c in rows.iter().chain(cols.iter()) {
+        for m in state.meta.get(c).into_iter().flatten() { *counts.entry(m.as_str()).or_insert(0) += 1; }
+    }
+    let mut over: Vec<(&str, usize)> = counts.into_iter().collect();
+    over.sort_unstable();
+    over.into_iter().filter_map(|(m, n)| {
+        let max_allowed = meta_cap(m, state);
+        (n > max_allowed).then(|| format!("Meta-category \"{}\" appears {} times (max {} allowed)", m, n, max_allowed))
+    }).collect()
+}
+
+// Number of words cell (r, c) can use once the other categories of the grid (`all`) are
+// taken into account; Err explains an empty cell.
+fn cell_words(r: &String, c: &String, all: &[&String], state: &State) -> Result<usize, String> {
+    cell_candidates(r, c, all, state).map(|words| words.len())
+}
+
+// The words behind cell_words.
+fn cell_candidates(r: &String, c: &String, all: &[&String], state: &State) -> Result<HashSet<String>, String> {
+    let rs = &state.cats[r];
+    let cs = &state.cats[c];
+    let mut inter = intersect(rs, cs);
+    let raw_len = inter.len();
+    if raw_len == 0 { return Err(format!("No unique word exists for cell ({}, {}) - raw intersection is empty (0 shared words)", r, c)); }
+    if state.max_share > 0 {
+        let others: Vec<&HashSet<String>> = all.iter().filter(|o| **o != r && **o != c).filter_map(|o| state.cats.get(*o)).collect();
+        let usable: HashSet<String> = inter.into_iter().filter(|w| others.iter().filter(|os| os.contains(w)).count() as u32 <= state.max_share).collect();
+        if usable.is_empty() { return Err(format!("No unique word exists for cell ({}, {}) - all {} shared word(s) appear in more than {} other categories", r, c, raw_len, state.max_share)); }
+        Ok(usable)
+    } else {
+        for o in all {
+            if *o != r && *o != c {
+                if let Some(os) = state.cats.get(*o) {
+                    inter = inter.drain().filter(|w| !os.contains(w)).collect();
+                    if inter.is_empty() {
+                        return Err(format!("No unique word exists for cell ({}, {}) - raw intersection has {} word(s), 0 remain after removing other categories; the last was removed by \"{}\"", r, c, raw_len, o));
+                    }
+                }
+            }
+        }
+        Ok(inter)
+    }
+}
+
+// Ok carries non-fatal notes; only uniqueSolution and softMeta produce any.
+fn validate(rows: [String;4], cols: [String;4], state: &State) -> Result<Vec<String>, String> {
     // existence
     for c in rows.iter().chain(cols.iter()) {
         if !state.cats.contains_key(c) { return Err(format!("Category \"{}\" not found in current word list", c)); }
     }
-    // meta
-    check_meta(&rows, &cols, state)?;
+    // meta (softMeta reports overflow as notes instead)
+    let mut notes = if state.soft_meta { meta_warnings(&rows, &cols, state) } else { check_meta(&rows, &cols, state).map(|_| Vec::new())? };
+    // identical word sets (mutual subsets) make a degenerate grid
+    let eight: Vec<&String> = rows.iter().chain(cols.iter()).collect();
+    for (a, &x) in eight.iter().enumerate() {
+        for &y in &eight[(a + 1)..] {
+            if x != y && state.cats[x] == state.cats[y] {
+                return Err(format!("Categories \"{}\" and \"{}\" have identical word sets", x, y));
+            }
+        }
+    }
     // unique cell words
-    let all: HashSet<&String> = rows.iter().chain(cols.iter()).collect();
+    let all: Vec<&String> = rows.iter().chain(cols.iter()).collect(); // in grid order, so the reported category is stable
     for r in &rows {
-        let rs = &state.cats[r];
         for c in &cols {
-            let cs = &state.cats[c];
-            let mut inter = intersect(rs, cs);
-            for o in &all { if *o != r && *o != c { if let Some(os) = state.cats.get(*o) { inter = inter.drain().filter(|w| !os.contains(w)).collect(); } } }
-            if inter.is_empty() { return Err(format!("No unique word exists for cell ({}, {}) - intersection is empty after removing words from other categories", r, c)); }
+            let remaining = cell_words(r, c, &all, state)?;
+            if state.unique_solution && remaining > 1 {
+                notes.push(format!("Cell ({}, {}) has {} candidate words", r, c, remaining));
+            }
         }
     }
-    Ok(())
+    Ok(notes)
+}
+
+// Quality score for a valid grid, from its cell counts, metas and category sizes:
+// sum over cells of 1/candidates (16 when every cell is forced), plus the number of distinct
+// metas, minus the mean log10 word count of the eight categories (broad ones are bland).
+fn grid_score(rows: [String;4], cols: [String;4], state: &State) -> Result<f64, String> {
+    validate(rows.clone(), cols.clone(), state)?;
+    let all: Vec<&String> = rows.iter().chain(cols.iter()).collect();
+    let mut cells = 0.0;
+    for r in &rows {
+        for c in &cols { cells += 1.0 / cell_words(r, c, &all, state)? as f64; }
+    }
+    let metas: HashSet<&String> = all.iter().flat_map(|c| state.meta.get(*c).into_iter().flatten()).collect();
+    let sizes = all.iter().map(|c| (state.cats[*c].len() as f64).log10()).sum::<f64>() / all.len() as f64;
+    Ok(cells + metas.len() as f64 - sizes)
+}
+
+// CountSolutions stops counting here and reports `many`.
+const SOLUTION_CAP: u64 = 1_000_000;
+// Search nodes CountSolutions may visit before giving up and reporting `many`; dead ends
+// cost steps without adding solutions, so SOLUTION_CAP alone does not bound the work.
+const FILLING_STEPS: u64 = 10_000_000;
+
+// Per-cell candidate words in row-major order, each sorted. Err for unknown categories; an
+// empty cell is just an empty list.
+fn cell_sets(rows: &[String;4], cols: &[String;4], state: &State) -> Result<Vec<Vec<String>>, String> {
+    let all: Vec<&String> = rows.iter().chain(cols.iter()).collect();
+    if let Some(c) = all.iter().find(|c| !state.cats.contains_key(**c)) {
+        return Err(format!("Category \"{}\" not found in current word list", c));
+    }
+    let mut sets = Vec::with_capacity(16);
+    for r in rows {
+        for c in cols {
+            let mut words: Vec<String> = cell_candidates(r, c, &all, state).map(|w| w.into_iter().collect()).unwrap_or_default();
+            words.sort_unstable();
+            sets.push(words);
+        }
+    }
+    Ok(sets)
+}
+
+// Backtracks over every assignment of a distinct word to each cell, calling `f` with the
+// chosen words (row-major); stops and returns false as soon as `f` does or `steps` (one per
+// search node) runs out. A branch is dropped as soon as some later cell has no unused word
+// left, so an empty or starved cell costs one level of the search, not all of them.
+fn for_each_filling<'a, F: FnMut(&[&'a str]) -> bool>(sets: &'a [Vec<String>], chosen: &mut Vec<&'a str>, steps: &mut u64, f: &mut F) -> bool {
+    if *steps == 0 { return false; }
+    *steps -= 1;
+    let Some(cands) = sets.get(chosen.len()) else { return f(chosen) };
+    for w in cands {
+        if chosen.contains(&w.as_str()) { continue; }
+        chosen.push(w);
+        let open = sets[chosen.len()..].iter().all(|s| s.iter().any(|x| !chosen.contains(&x.as_str())));
+        let more = !open || for_each_filling(sets, chosen, steps, f);
+        chosen.pop();
+        if !more { return false; }
+    }
+    true
+}
+
+// Number of fillings, capped at SOLUTION_CAP (the bool is true when capped, or when the
+// search used up its `steps` first). With pairwise disjoint cells (always the case when
+// maxShare is 0) it is just the product of the sizes.
+fn count_fillings(sets: &[Vec<String>], steps: u64) -> (u64, bool) {
+    if sets.iter().any(|s| s.is_empty()) { return (0, false); }
+    let mut seen: HashSet<&String> = HashSet::new();
+    if sets.iter().flatten().all(|w| seen.insert(w)) {
+        let product = sets.iter().try_fold(1u64, |acc, s| acc.checked_mul(s.len() as u64).filter(|&p| p < SOLUTION_CAP));
+        return product.map_or((SOLUTION_CAP, true), |p| (p, false));
+    }
+    let (mut count, mut steps) = (0u64, steps);
+    for_each_filling(sets, &mut Vec::with_capacity(sets.len()), &mut steps, &mut |_| { count += 1; count < SOLUTION_CAP });
+    (count, count >= SOLUTION_CAP || steps == 0)
+}
+
+// Category pairs whose word sets have Jaccard similarity >= threshold, sorted by name.
+fn near_duplicates(state: &State, threshold: f64) -> Vec<(String, String, f64)> {
+    let mut names: Vec<&String> = state.cats.keys().collect();
+    names.sort();
+    let mut pairs = Vec::new();
+    for (a, &na) in names.iter().enumerate() {
+        let sa = &state.cats[na];
+        for &nb in &names[(a + 1)..] {
+            let sb = &state.cats[nb];
+            let (lo, hi) = if sa.len() < sb.len() { (sa.len(), sb.len()) } else { (sb.len(), sa.len()) };
+            // Jaccard can never exceed |small| / |large|
+            if hi == 0 || (lo as f64) / (hi as f64) < threshold { continue; }
+            let inter = intersect(sa, sb).len();
+            let j = inter as f64 / (sa.len() + sb.len() - inter) as f64;
+            if j >= threshold { pairs.push((na.clone(), nb.clone(), j)); }
+        }
+    }
+    pairs
 }
 
 fn main() {
     let stdin = std::io::stdin();
     let mut reader = BufReader::new(stdin.lock());
-    let mut line = String::new();
+    let enc = Encoding::from_env();
+    let mut line = Vec::new();
     let mut state_opt: Option<State> = None;
     let mut stdout = std::io::stdout();
 
     loop {
-        line.clear();
-        let n = reader.read_line(&mut line).unwrap();
-        if n == 0 { break; }
-        let msg: Msg = match serde_json::from_str(&line) {
-            Ok(m) => m,
-            Err(e) => { let _ = writeln!(stdout, "{}", serde_json::to_string(&Out::Invalid{ reason: format!("bad json: {}", e)}).unwrap()); continue; }
+        let msg: Msg = match wire::read_msg(&mut reader, enc, &mut line) {
+            None => break,
+            Some(Ok(m)) => m,
+            Some(Err(e)) => { wire::send(&mut stdout, enc, &Out::Invalid{ reason: e }); continue; }
         };
         match msg {
-            Msg::Init { categories, meta_map } => {
+            Msg::Init { categories, meta_map, maxShare, uniqueSolution, softMeta, distinctMeta } => {
+                // a meta keyed by a category that doesn't exist means the word list and meta file drifted
+                let mut unknown: Vec<&str> = meta_map.keys().filter(|c| !categories.contains_key(*c)).map(String::as_str).collect();
+                if !unknown.is_empty() {
+                    unknown.sort_unstable();
+                    wire::send(&mut stdout, enc, &Out::Invalid{ reason: format!("meta_map names unknown categories: {}", unknown.join(", ")) });
+                    continue;
+                }
                 let cats = categories.into_iter().map(|(k, v)| (k, v.into_iter().collect())).collect();
-                let state = State { cats, meta: meta_map };
+                let state = State {
+                    cats,
+                    meta: meta_map.into_iter().map(|(c, m)| (c, m.into())).collect(),
+                    max_share: maxShare.unwrap_or(0),
+                    unique_solution: uniqueSolution.unwrap_or(false),
+                    soft_meta: softMeta.unwrap_or(false),
+                    distinct_meta: distinctMeta.unwrap_or(false),
+                };
                 state_opt = Some(state);
-                let _ = writeln!(stdout, "{}", serde_json::to_string(&Out::Ready).unwrap());
+                wire::send(&mut stdout, enc, &Out::Ready);
             }
             Msg::Validate { rows, cols } => {
                 if let Some(ref state) = state_opt {
                     match validate(rows, cols, state) {
-                        Ok(()) => { let _ = writeln!(stdout, "{}", serde_json::to_string(&Out::Valid).unwrap()); }
-                        Err(reason) => { let _ = writeln!(stdout, "{}", serde_json::to_string(&Out::Invalid{ reason }).unwrap()); }
+                        Ok(notes) => { wire::send(&mut stdout, enc, &Out::Valid{ notes }); }
+                        Err(reason) => { wire::send(&mut stdout, enc, &Out::Invalid{ reason }); }
+                    }
+                } else {
+                    wire::send(&mut stdout, enc, &Out::Invalid{ reason: "not initialized".into()});
+                }
+            }
+            Msg::CellCounts { rows, cols } => {
+                if let Some(ref state) = state_opt {
+                    let all: Vec<&String> = rows.iter().chain(cols.iter()).collect();
+                    let mut counts = [[0usize; 4]; 4];
+                    for (ri, r) in rows.iter().enumerate() {
+                        for (ci, c) in cols.iter().enumerate() {
+                            if state.cats.contains_key(r) && state.cats.contains_key(c) {
+                                counts[ri][ci] = cell_words(r, c, &all, state).unwrap_or(0);
+                            }
+                        }
+                    }
+                    let reason = validate(rows.clone(), cols.clone(), state).err();
+                    wire::send(&mut stdout, enc, &Out::CellCounts{ counts, reason });
+                } else {
+                    wire::send(&mut stdout, enc, &Out::Invalid{ reason: "not initialized".into()});
+                }
+            }
+            Msg::Rescore { items } => {
+                if let Some(ref state) = state_opt {
+                    let mut scores = Vec::with_capacity(items.len());
+                    for (hash, rows, cols) in items {
+                        match grid_score(rows, cols, state) {
+                            Ok(score) => scores.push((hash, score)),
+                            Err(reason) => wire::send(&mut stdout, enc, &Out::Failed{ hash, reason }),
+                        }
+                    }
+                    wire::send(&mut stdout, enc, &Out::Scores{ items: scores });
+                } else {
+                    wire::send(&mut stdout, enc, &Out::Invalid{ reason: "not initialized".into()});
+                }
+            }
+            Msg::Ping => {
+                wire::send(&mut stdout, enc, &Out::Pong);
+            }
+            Msg::CountSolutions { rows, cols } => {
+                if let Some(ref state) = state_opt {
+                    match cell_sets(&rows, &cols, state) {
+                        Ok(sets) => {
+                            let (count, many) = count_fillings(&sets, FILLING_STEPS);
+                            wire::send(&mut stdout, enc, &Out::SolutionCount{ count, many });
+                        }
+                        Err(reason) => wire::send(&mut stdout, enc, &Out::Invalid{ reason }),
+                    }
+                } else {
+                    wire::send(&mut stdout, enc, &Out::Invalid{ reason: "not initialized".into()});
+                }
+            }
+            Msg::EnumerateSolutions { rows, cols, limit } => {
+                if let Some(ref state) = state_opt {
+                    match cell_sets(&rows, &cols, state) {
+                        Ok(sets) => {
+                            let mut emitted = 0usize;
+                            // an empty cell means no filling; don't even start the search
+                            if limit > 0 && sets.iter().all(|s| !s.is_empty()) {
+                                let mut steps = u64::MAX;
+                                for_each_filling(&sets, &mut Vec::with_capacity(sets.len()), &mut steps, &mut |words| {
+                                    let cells: [[String; 4]; 4] = std::array::from_fn(|r| std::array::from_fn(|c| words[r * 4 + c].to_string()));
+                                    wire::send(&mut stdout, enc, &Out::Solution{ cells });
+                                    emitted += 1;
+                                    emitted < limit
+                                });
+                            }
+                            wire::send(&mut stdout, enc, &Out::SolutionsDone{ emitted });
+                        }
+                        Err(reason) => wire::send(&mut stdout, enc, &Out::Invalid{ reason }),
                     }
                 } else {
-                    let _ = writeln!(stdout, "{}", serde_json::to_string(&Out::Invalid{ reason: "not initialized".into()}).unwrap());
+                    wire::send(&mut stdout, enc, &Out::Invalid{ reason: "not initialized".into()});
+                }
+            }
+            Msg::FindNearDuplicates { jaccard } => {
+                if let Some(ref state) = state_opt {
+                    let pairs = near_duplicates(state, jaccard);
+                    wire::send(&mut stdout, enc, &Out::Pairs{ pairs });
+                } else {
+                    wire::send(&mut stdout, enc, &Out::Invalid{ reason: "not initialized".into()});
+                }
+            }
+            Msg::ValidateStored { items } => {
+                if let Some(ref state) = state_opt {
+                    let checked = items.len();
+                    let mut failed = 0usize;
+                    for (rows, cols, hash) in items {
+                        if let Err(reason) = validate(rows, cols, state) {
+                            failed += 1;
+                            wire::send(&mut stdout, enc, &Out::Failed{ hash, reason });
+                        }
+                    }
+                    wire::send(&mut stdout, enc, &Out::Done{ checked, failed });
+                } else {
+                    wire::send(&mut stdout, enc, &Out::Invalid{ reason: "not initialized".into()});
                 }
             }
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdx_helper::rng::SplitMix64;
+
+    fn cells(words: &[&[&str]]) -> Vec<Vec<String>> {
+        words.iter().map(|c| c.iter().map(|w| w.to_string()).collect()).collect()
+    }
+
+    // Every combination of one word per cell, keeping those with no word used twice.
+    fn brute_force(sets: &[Vec<String>]) -> u64 {
+        if sets.iter().any(|s| s.is_empty()) { return 0; }
+        let mut idx = vec![0usize; sets.len()];
+        let mut count = 0;
+        loop {
+            let words: Vec<&String> = idx.iter().zip(sets).map(|(&k, s)| &s[k]).collect();
+            if words.iter().enumerate().all(|(a, w)| !words[(a + 1)..].contains(w)) { count += 1; }
+            let Some(pos) = (0..sets.len()).rev().find(|&p| idx[p] + 1 < sets[p].len()) else { return count };
+            idx[pos] += 1;
+            for k in &mut idx[(pos + 1)..] { *k = 0; }
+        }
+    }
+
+    #[test]
+    fn count_fillings_matches_brute_force() {
+        let fixed = [
+            cells(&[&["a", "b"], &["c"], &["d", "e", "f"]]),      // disjoint: product path
+            cells(&[&["a", "b"], &["a", "b"], &["a", "b", "c"]]), // shared words: backtracking
+            cells(&[&["a"], &["a"], &["b"]]),                     // no filling at all
+            cells(&[&["a", "b"], &[], &["c"]]),                   // an empty cell
+        ];
+        for sets in &fixed {
+            assert_eq!(count_fillings(sets, FILLING_STEPS), (brute_force(sets), false));
+        }
+        let mut rng = SplitMix64::new(11);
+        for _ in 0..200 {
+            let n = 2 + rng.below(5);
+            let sets: Vec<Vec<String>> = (0..n).map(|_| {
+                let mut words: Vec<String> = (0..rng.below(5)).map(|_| format!("w{}", rng.below(8))).collect();
+                words.sort_unstable();
+                words.dedup();
+                words
+            }).collect();
+            assert_eq!(count_fillings(&sets, FILLING_STEPS), (brute_force(&sets), false), "{:?}", sets);
+        }
+    }
+
+    #[test]
+    fn count_fillings_stops_at_the_cap() {
+        // 3^16 disjoint fillings, past the cap on the product path
+        let disjoint: Vec<Vec<String>> = (0..16).map(|c| (0..3).map(|w| format!("c{}w{}", c, w)).collect()).collect();
+        assert_eq!(count_fillings(&disjoint, FILLING_STEPS), (SOLUTION_CAP, true));
+        // 17! / 1! fillings of 16 cells drawing from the same 17 words, past it when backtracking
+        let shared: Vec<Vec<String>> = vec![(0..17).map(|w| format!("w{}", w)).collect(); 16];
+        assert_eq!(count_fillings(&shared, FILLING_STEPS), (SOLUTION_CAP, true));
+        // 10^6 disjoint fillings: reaching the cap exactly counts as capped too
+        let at_cap: Vec<Vec<String>> = (0..6).map(|c| (0..10).map(|w| format!("c{}w{}", c, w)).collect()).collect();
+        assert_eq!(count_fillings(&at_cap, FILLING_STEPS), (SOLUTION_CAP, true));
+    }
 
+    #[test]
+    fn count_fillings_bounds_dead_end_searches() {
+        // shared words force the backtracking path; the empty last cell means no filling
+        let mut sets: Vec<Vec<String>> = (0..15).map(|c| (0..6).map(|w| format!("w{}", (c + w) % 20)).collect()).collect();
+        sets.push(Vec::new());
+        assert_eq!(count_fillings(&sets, FILLING_STEPS), (0, false));
+        // 15 cells over the same 14 words: no filling, but no empty cell to spot it up front
+        let crowded: Vec<Vec<String>> = vec![(0..14).map(|w| format!("w{}", w)).collect(); 15];
+        assert_eq!(count_fillings(&crowded, 100_000), (0, true));
+    }
+
+    #[test]
+    fn for_each_filling_stops_when_told() {
+        let sets = cells(&[&["a", "b", "c"], &["a", "b", "c"]]);
+        let mut seen = Vec::new();
+        let done = for_each_filling(&sets, &mut Vec::new(), &mut 100, &mut |w| { seen.push(w.join("")); seen.len() < 4 });
+        assert!(!done);
+        assert_eq!(seen, ["ab", "ac", "ba", "bc"]);
+    }
+
+    #[test]
+    fn for_each_filling_prunes_an_empty_last_cell() {
+        let mut sets: Vec<Vec<String>> = (0..15).map(|c| (0..6).map(|w| format!("w{}", (c + w) % 20)).collect()).collect();
+        sets.push(Vec::new());
+        let mut steps = 100;
+        let mut found = 0;
+        assert!(for_each_filling(&sets, &mut Vec::new(), &mut steps, &mut |_| { found += 1; true }));
+        assert_eq!(found, 0);
+        assert!(steps > 0);
+    }
+}
@@ -1,252 +1,1742 @@
 use serde::{Deserialize, Serialize};
-use std::io::{BufRead, BufReader, Write};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use cdx_helper::adjacency::{build, count_common, insert_sorted, patch_changed, shares_enough};
+use cdx_helper::bitset::{intersects, pack_single, repack_limbs, subset, subset_matrix, BitMatrix};
+use cdx_helper::cache::{read_adjacency, read_bundle};
+use cdx_helper::hash::{self, puzzle_hash};
+use cdx_helper::meta::MetaTags;
+use cdx_helper::rng::SplitMix64;
+use cdx_helper::wire::{self, Encoding};
+use rayon::prelude::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::io::{BufReader, Write};
+use std::sync::mpsc;
+
+const DEFAULT_MAX_MEM_BYTES: u64 = 4 << 30;
+const EXPLAIN_PER_I: usize = 100; // Rejected messages per i in explain mode
+const VERIFY_MISMATCHES: usize = 5; // mismatching lists VerifyAdjacency reports
+const PAR_CHUNK: usize = 4096; // column sets gathered per parallel batch (threads > 1)
 
 #[derive(Deserialize)]
 #[serde(tag = "type")]
+#[allow(non_snake_case, clippy::large_enum_variant)] // one message at a time, so Init's size doesn't matter
 enum Msg {
     Init {
-        masks: Vec<Vec<u32>>, // bitsets per category
-        n1: Vec<Vec<usize>>,  // adjacency 1-away
+        #[serde(default)]
+        masks: Vec<Vec<u64>>, // bitsets per category, in limb_bits-wide limbs
+        limb_bits: Option<u32>, // 32 (default) or 64 for masks here and in AddCategories/UpdateMask
+        #[serde(default)]
+        n1: Vec<Vec<usize>>,  // adjacency 1-away (computed from masks when n1 and n2 are omitted)
+        #[serde(default)]
         n2: Vec<Vec<usize>>,  // adjacency 2-away
+        adjacency_path: Option<String>, // load n1/n2 from this cache file instead of the arrays above
+        cache_path: Option<String>,     // load masks, n1/n2, categories and meta_map from a cdx_helper bundle
+        word_list_hash: Option<String>, // must match the cache's header (required for adjacency_path)
+        verifyWordListHash: Option<bool>, // require word_list_hash == the sha256 of words_path, as solve_and_curate.js hashes it
+        words_path: Option<String>,     // the words.json the masks index (needed by verifyWordListHash)
+        #[serde(default)]
+        categories: Vec<String>,
+        #[serde(default)]
+        meta_map: Vec<MetaTags>,       // metas per category index (null, a name, or a list of names)
+        emitWords: Option<bool>,       // include a representative word bit per cell in Found
+        emitCellCounts: Option<bool>,  // include the unique-word count per cell in Found
+        emitDistinctWords: Option<bool>, // include the grid-wide unique-word count in Found
+        hardestCells: Option<usize>,   // include the K cells with the fewest unique words in Found (default 0 = off)
+        emitConnectivity: Option<bool>, // include the grid's summed n2 path counts in Found
+        emitPuzzleHash: Option<bool>,  // include the DB's puzzle_hash of the grid in Found
+        metaReport: Option<bool>,      // after each Work / WorkBatch, send MetaReport of grids per meta
+        cooccurrence: Option<bool>,    // after each Work / WorkBatch, send Cooccurrence of category pairs
+        emitDifficulty: Option<bool>,  // include the weakest cell's ease in Found
+        wordWeights: Option<Vec<f64>>, // per-word frequency by bit position (missing bits weigh 0); drives difficulty
+        wordCategoryCounts: Option<Vec<u32>>, // categories containing each word, by bit position; adds rarity to Found
+        uniqueSolution: Option<bool>,  // require exactly one unique word per cell
+        distinctMeta: Option<bool>,    // cap every meta at one category per grid
+        softMeta: Option<bool>,        // allow meta overflow, penalizing Found's score instead of rejecting
+        metaPenalty: Option<f64>,      // score penalty per category over a meta cap (default 1)
+        maxShare: Option<u32>,         // a cell word may also appear in up to this many of the other categories (default 0)
+        tickEvery: Option<usize>,      // emit a Tick every N j's (default 2; 0 = only the final tick per i)
+        minCategorySize: Option<u32>,  // categories with fewer words never appear in a grid
+        maxCategorySize: Option<u32>,  // categories with more words never appear in a grid
+        #[serde(default)]
+        excludeMetas: Vec<String>,     // categories with any of these metas never appear in a grid
+        maxN2Degree: Option<usize>,    // categories with more n2 neighbours (hubs) never appear in a grid
+        maxCandidateCols: Option<usize>, // skip row sets with more column candidates than this, with a Warning
+        allowSubsetEdges: Option<bool>, // let subset-related categories share a grid (default false)
+        minSharedWords: Option<u32>,   // n2 pairs must also share this many words, as in cdx_helper (default 0 = off)
+        profile: Option<bool>,         // emit ITiming after each i of a Work
+        compactFound: Option<bool>,    // emit grids as Packed (base64 of rows then cols as u16 LE) instead of Found
+        maxMemBytes: Option<u64>,      // refuse to Init if the subset matrix would exceed this (default 4 GiB)
+        threads: Option<usize>,        // check each row set's column sets on this many threads (default 1)
+        rows_n: Option<usize>,         // grid shape (default 4 x 4)
+        cols_n: Option<usize>,
+    },
+    AddCategories {
+        masks: Vec<Vec<u64>>, // appended after the existing categories
         categories: Vec<String>,
-        meta_map: Vec<Option<String>>, // meta per category index (or None)
+        meta_map: Vec<MetaTags>,
+    },
+    UpdateMask {
+        index: usize,   // category whose words changed
+        mask: Vec<u64>, // its new mask; the subset row/column and n1/n2 edges are patched in O(n)
+    },
+    CompleteRows {
+        rows: Vec<usize>, // fixed row categories (rows_n of them); streams every valid column set
+    },
+    CompleteThreeRows {
+        rows: [usize; 3], // three mutually n2-connected rows of a 4-row grid; tries every fourth row
+    },
+    Categories, // returns the category names in index order
+    Ping,       // liveness probe: answered with Pong, works before Init too
+    VerifyAdjacency, // recompute n1/n2 from the masks: Valid, or Error naming the first mismatches
+    FindOne {
+        seed: Option<u64>, // fixes the random starting point (otherwise time-seeded)
+    },
+    Sample {
+        start: usize, // i range to enumerate, as in Work
+        end: usize,
+        k: usize,     // grids to return, uniformly at random among the distinct valid ones
+        seed: u64,
     },
     Work {
         start: usize,
         end: usize,
         jStart: Option<usize>,
         jEnd: Option<usize>,
+        seed: Option<u64>,    // visit i's in a seeded random order instead of ascending
+        shuffleJ: Option<bool>, // with a seed, also shuffle each i's j slice
+        checkpoint_path: Option<String>, // record progress here and skip what it says is done for the same range
+        skipUniqueness: Option<bool>, // skip the per-cell check; emits false positives for the cleaner to drop
+        explain: Option<bool>,  // emit Rejected for column sets that fail the final checks (capped per i)
+    },
+    WorkBatch {
+        ranges: Vec<(usize, usize, Option<usize>, Option<usize>)>, // (start, end, jStart, jEnd), run in order with Work's defaults
     },
+    Cancel, // stops a running WorkBatch at its next range boundary; ignored otherwise
 }
 
 #[derive(Serialize)]
 #[serde(tag = "type")]
+#[allow(non_snake_case)]
 enum Out {
     Ready,
-    Tick { jProgress: usize, totalJ: usize },
-    Found { rows: [usize; 4], cols: [usize; 4] },
+    Tick { jProgress: usize, totalJ: usize, foundSoFar: usize }, // foundSoFar counts the whole Work
+    Found {
+        rows: Vec<usize>,
+        cols: Vec<usize>,
+        // words[r][c] = bit index of the lowest unique word in cell (r, c)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        words: Option<CellGrid>,
+        // cell_counts[r][c] = number of words unique to cell (r, c)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cell_counts: Option<CellGrid>,
+        // unique words across all cells combined
+        #[serde(skip_serializing_if = "Option::is_none")]
+        distinctWords: Option<u32>,
+        // hardestCells: (row, col) positions of the most constrained cells, fewest words first
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hardestCells: Option<Vec<(usize, usize)>>,
+        // sum of 2-hop path counts over the row pairs and column pairs
+        #[serde(skip_serializing_if = "Option::is_none")]
+        connectivity: Option<u32>,
+        // sha256 of the row then column names, as stored in the puzzles table
+        #[serde(skip_serializing_if = "Option::is_none")]
+        puzzle_hash: Option<String>,
+        // emitDifficulty: ease of the weakest cell, the largest wordWeights entry among its
+        // unique words (its unique word count without weights); lower is harder
+        #[serde(skip_serializing_if = "Option::is_none")]
+        difficulty: Option<f64>,
+        // wordCategoryCounts: mean over cells of 1 / the category count of the cell's rarest
+        // unique word, in (0, 1]; higher means rarer words
+        #[serde(skip_serializing_if = "Option::is_none")]
+        rarity: Option<f64>,
+        // softMeta only: -metaPenalty per category beyond a meta's cap (0 when within caps)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        score: Option<f64>,
+    },
+    Packed { g: String },
     Done { totalJ: usize },
+    BatchDone { ranges: usize }, // after the last range's Done: ranges completed (fewer after a Cancel)
+    Sampled { total: usize },    // after a Sample's grids: how many distinct valid grids it drew from
+    MetaReport { counts: Vec<(String, usize)> }, // grids emitted per meta (each grid counted once per meta), by name
+    Cooccurrence { pairs: Vec<(usize, usize, usize)> }, // (a, b, grids with both) for a < b, nonzero only, ascending
+    Added { added: usize, total: usize },
+    Updated { index: usize },
+    Categories { categories: Vec<String> },
+    ITiming { i: usize, elapsed_ms: u64, found: usize },
+    Rejected { rows: Vec<usize>, cols: Vec<usize>, reason: String },
+    HubsExcluded { categories: Vec<usize> }, // newly dropped by maxN2Degree, reported once
+    MetasExcluded { removed: usize }, // after Init with excludeMetas: categories it pruned
     Error { message: String },
+    Warning { message: String },
+    Pong,
+    Valid, // VerifyAdjacency: n1/n2 match the masks
 }
 
 struct State {
     masks: Vec<Vec<u32>>, // immutable
+    single: Option<Vec<u64>>, // masks as one u64 each when they fit (pack_single), for the fast paths
+    limb_bits: u32,       // width of incoming mask limbs
     n1: Vec<Vec<usize>>,  // sorted
     n2: Vec<Vec<usize>>,  // sorted
     categories: Vec<String>,
-    meta_map: Vec<Option<String>>, // same length as categories
-    subset: Vec<Vec<bool>>, // S[i][j]
+    meta_map: Vec<Vec<String>>, // same length as categories; a category may have several metas
+    subset: BitMatrix, // S[i][j]: masks[i] ⊆ masks[j]
+    emit_words: bool,
+    emit_cell_counts: bool,
+    emit_distinct_words: bool,
+    hardest_cells: usize,
+    emit_connectivity: bool,
+    emit_puzzle_hash: bool,
+    emit_difficulty: bool,
+    meta_report: bool,
+    cooccurrence: bool,
+    word_weights: Option<Vec<f64>>,
+    word_counts: Option<Vec<u32>>, // wordCategoryCounts
+    max_share: u32,
+    unique_solution: bool,
+    soft_meta: bool,
+    distinct_meta: bool,
+    meta_penalty: f64,
+    tick_every: usize,
+    rows_n: usize, // grid shape, both >= 2
+    cols_n: usize,
+    profile: bool,
+    compact_found: bool,
+    max_mem_bytes: u64, // maxMemBytes, checked again whenever AddCategories grows the subset matrix
+    size_range: (u32, u32), // allowed word count per category (inclusive)
+    max_n2_degree: usize,
+    max_candidate_cols: usize,
+    allow_subset_edges: bool,
+    min_shared_words: u32,
+    exclude_metas: Vec<String>,
+    excluded: Vec<bool>,    // categories skipped by every candidate pool (n1/n2 still list them)
+    pool: Option<rayon::ThreadPool>, // set when Init asked for threads > 1
 }
 
-fn intersects(a: &[u32], b: &[u32]) -> bool {
-    a.iter().zip(b.iter()).any(|(x, y)| (x & y) != 0)
+// Per-Work parameters, resolved from Msg::Work with defaults applied.
+struct WorkParams {
+    start: usize,
+    end: usize,
+    j_start: Option<usize>,
+    j_end: Option<usize>,
+    seed: Option<u64>,
+    shuffle_j: bool,
+    skip_uniqueness: bool,
+    explain: bool,
+    checkpoint_path: Option<String>,
 }
 
-fn subset(a: &[u32], b: &[u32]) -> bool {
-    a.iter().zip(b.iter()).all(|(x, y)| (x & !y) == 0)
+// Work parameters that fix the visit order; a checkpoint only applies to an identical range.
+type WorkRange = (usize, usize, Option<usize>, Option<usize>, Option<u64>, bool);
+
+// Progress through a Work's visit order, saved under checkpoint_path.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    range: WorkRange,
+    i_done: usize, // i's fully searched, counted in visit order
+    j_done: usize, // j's fully searched within the next i
+}
+
+// (i_done, j_done) to resume from; (0, 0) when there is no checkpoint for this range.
+fn load_checkpoint(path: &str, range: WorkRange) -> (usize, usize) {
+    std::fs::read(path).ok()
+        .and_then(|b| serde_json::from_slice::<Checkpoint>(&b).ok())
+        .filter(|c| c.range == range)
+        .map_or((0, 0), |c| (c.i_done, c.j_done))
+}
+
+// Writes to a temp file first so a crash never leaves a torn checkpoint.
+fn save_checkpoint(path: &str, c: &Checkpoint) -> std::io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, serde_json::to_vec(c).unwrap())?;
+    std::fs::rename(&tmp, path)
+}
+
+// save_checkpoint under `path`; the first failure is reported as a Warning and clears `path`,
+// so the rest of the Work runs without checkpoints.
+fn save_progress<W: Write>(path: &mut Option<&str>, c: Checkpoint, enc: Encoding, writer: &mut W) {
+    let Some(p) = *path else { return };
+    if let Err(e) = save_checkpoint(p, &c) {
+        wire::send(writer, enc, &Out::Warning { message: format!("cannot save checkpoint {}: {}; continuing without checkpoints", p, e) });
+        *path = None;
+    }
 }
 
-fn check_meta_constraint(rows: &[usize; 4], cols: &[usize; 4], state: &State) -> bool {
+// Whether category i carries one of the excludeMetas.
+fn has_excluded_meta(i: usize, state: &State) -> bool {
+    state.meta_map[i].iter().any(|m| state.exclude_metas.contains(m))
+}
+
+// Marks categories that fail the Init filters, so the search never chooses them as a row or
+// a column. n1/n2 stay complete (AddCategories and UpdateMask recount shared neighbours from
+// them); only the candidate pools skip excluded entries. Exclusions are sticky; returns the
+// hubs newly excluded by maxN2Degree.
+fn apply_exclusions(state: &mut State) -> Vec<usize> {
+    let (lo, hi) = state.size_range;
+    let prev = std::mem::take(&mut state.excluded);
+    let mut hubs = Vec::new();
+    state.excluded = state.masks.iter().enumerate().map(|(i, m)| {
+        if prev.get(i).copied().unwrap_or(false) { return true; }
+        let size: u32 = m.iter().map(|x| x.count_ones()).sum();
+        if size < lo || size > hi || has_excluded_meta(i, state) { return true; }
+        let hub = state.n2[i].len() > state.max_n2_degree;
+        if hub { hubs.push(i); }
+        hub
+    }).collect();
+    hubs
+}
+
+// Peak memory of adjacency::build over n categories: its own subset matrix plus the n × n
+// shared-neighbour counts.
+fn build_bytes(n: usize) -> u64 {
+    BitMatrix::bytes_for(n) + (n as u64).pow(2)
+}
+
+// Rebuilds n1/n2 from the masks as cdx_helper would (with this Init's allow_subset_edges and
+// min_shared_words) and describes the first lists that differ from the ones held. Err when
+// the rebuild would not fit maxMemBytes.
+fn verify_adjacency(state: &State) -> Result<Vec<String>, String> {
+    let n = state.masks.len();
+    if build_bytes(n) > state.max_mem_bytes {
+        return Err(format!("adjacency build for {} categories needs {} bytes, over the maxMemBytes budget of {}", n, build_bytes(n), state.max_mem_bytes));
+    }
+    let (n1, n2) = build(&state.masks, state.allow_subset_edges, state.min_shared_words);
+    let mut mismatches = Vec::new();
+    for (name, held, expected) in [("n1", &state.n1, &n1), ("n2", &state.n2, &n2)] {
+        for (i, (h, e)) in held.iter().zip(expected).enumerate() {
+            if h == e { continue; }
+            if mismatches.len() == VERIFY_MISMATCHES { return Ok(mismatches); }
+            let missing: Vec<usize> = e.iter().copied().filter(|x| h.binary_search(x).is_err()).collect();
+            let extra: Vec<usize> = h.iter().copied().filter(|x| e.binary_search(x).is_err()).collect();
+            mismatches.push(format!("{}[{}] missing {:?}, extra {:?}", name, i, missing, extra));
+        }
+    }
+    Ok(mismatches)
+}
+
+// Appends categories and patches the subset matrix and n1/n2 using the same rules as
+// cdx_helper: no edges between subset-related pairs (unless allow_subset_edges), n2 needs >= 4 shared n1 neighbours
+// (and min_shared_words common words).
+fn add_categories(state: &mut State, masks: Vec<Vec<u64>>, categories: Vec<String>, meta_map: Vec<MetaTags>) -> Result<(usize, Vec<usize>), String> {
+    let masks = repack_limbs(masks, state.limb_bits)?;
+    let mask_len = state.masks[0].len();
+    if masks.len() != categories.len() || masks.len() != meta_map.len() {
+        return Err(format!("AddCategories length mismatch: {} masks, {} categories, {} meta entries", masks.len(), categories.len(), meta_map.len()));
+    }
+    if let Some(m) = masks.iter().find(|m| m.len() != mask_len) {
+        return Err(format!("AddCategories mask has {} limbs, expected {}", m.len(), mask_len));
+    }
+    let old_n = state.masks.len();
+    let added = masks.len();
+    if state.compact_found && old_n + added > u16::MAX as usize + 1 {
+        return Err(format!("compactFound packs indices as u16, but AddCategories would bring the total to {} categories", old_n + added));
+    }
+    let needed = BitMatrix::bytes_for(old_n + added);
+    if needed > state.max_mem_bytes {
+        return Err(format!("subset matrix for {} categories needs {} bytes, over the maxMemBytes budget of {}", old_n + added, needed, state.max_mem_bytes));
+    }
+    state.masks.extend(masks);
+    state.categories.extend(categories);
+    state.meta_map.extend(meta_map.into_iter().map(Vec::from));
+    state.single = pack_single(&state.masks);
+    let n = state.masks.len();
+
+    let mut sub = state.subset.grown(n);
+    for i in 0..n {
+        // old × old entries are already known
+        let from = if i >= old_n { 0 } else { old_n };
+        for j in from..n {
+            if i != j && subset(&state.masks[i], &state.masks[j]) { sub.set(i, j, true); }
+        }
+    }
+    state.subset = sub;
+    let allow_subset = state.allow_subset_edges;
+    let related = |s: &BitMatrix, a: usize, b: usize| !allow_subset && (s.get(a, b) || s.get(b, a));
+
+    state.n1.resize(n, Vec::new());
+    state.n2.resize(n, Vec::new());
+    for x in old_n..n {
+        for y in 0..x {
+            if !related(&state.subset, x, y) && intersects(&state.masks[x], &state.masks[y]) {
+                insert_sorted(&mut state.n1[x], y);
+                insert_sorted(&mut state.n1[y], x);
+            }
+        }
+    }
+
+    // Pairs whose shared-neighbour count can have changed: anything touching a new index,
+    // plus old pairs that both neighbour a new index.
+    let mut pairs: HashSet<(usize, usize)> = HashSet::new();
+    for x in old_n..n {
+        for y in 0..n { if y != x { pairs.insert((x.min(y), x.max(y))); } }
+        let nb: Vec<usize> = state.n1[x].iter().copied().filter(|&y| y < old_n).collect();
+        for (a, &p) in nb.iter().enumerate() {
+            for &q in &nb[(a + 1)..] { pairs.insert((p, q)); }
+        }
+    }
+    for (a, b) in pairs {
+        if related(&state.subset, a, b) { continue; }
+        if count_common(&state.n1[a], &state.n1[b]) >= 4 && shares_enough(&state.masks[a], &state.masks[b], state.min_shared_words) {
+            insert_sorted(&mut state.n2[a], b);
+            insert_sorted(&mut state.n2[b], a);
+        }
+    }
+    let hubs = apply_exclusions(state);
+    Ok((added, hubs))
+}
+
+// How many of a grid's categories may share meta `m`.
+fn meta_cap(m: &str, state: &State) -> usize {
+    if state.distinct_meta || m == "Letter Patterns" { 1 } else { 2 }
+}
+
+// Length invariants State relies on, checked once Init has resolved its masks and adjacency.
+fn check_init_lengths(masks: &[Vec<u32>], n1: &[Vec<usize>], n2: &[Vec<usize>], categories: &[String], meta_map: &[Vec<String>]) -> Result<(), String> {
+    let n = masks.len();
+    if n == 0 { return Err("Init has no masks".into()); }
+    if let Some((i, m)) = masks.iter().enumerate().find(|(_, m)| m.len() != masks[0].len()) {
+        return Err(format!("masks[{}] has {} limbs but masks[0] has {}", i, m.len(), masks[0].len()));
+    }
+    for (name, len) in [("n1", n1.len()), ("n2", n2.len()), ("categories", categories.len()), ("meta_map", meta_map.len())] {
+        if len != n { return Err(format!("{} has {} entries but there are {} masks", name, len, n)); }
+    }
+    for (name, adj) in [("n1", n1), ("n2", n2)] {
+        if let Some((i, &x)) = adj.iter().enumerate().find_map(|(i, l)| l.iter().find(|&&x| x >= n).map(|x| (i, x))) {
+            return Err(format!("{}[{}] lists category {}, but there are only {}", name, i, x, n));
+        }
+    }
+    Ok(())
+}
+
+// Replaces one category's mask, recomputing its subset row and column and (via the shared
+// patch_changed) every n1/n2 edge the edit can move. patch_changed recounts from the complete
+// lists, which excluded categories stay in; exclusions are then re-applied.
+fn update_mask(state: &mut State, index: usize, mask: Vec<u64>) -> Result<Vec<usize>, String> {
+    let mask = repack_limbs(vec![mask], state.limb_bits)?.remove(0);
+    let n = state.masks.len();
+    if index >= n { return Err(format!("UpdateMask index {} out of range ({} categories)", index, n)); }
+    if mask.len() != state.masks[index].len() {
+        return Err(format!("UpdateMask mask has {} limbs, expected {}", mask.len(), state.masks[index].len()));
+    }
+    state.masks[index] = mask;
+    state.single = pack_single(&state.masks);
+    for j in 0..n {
+        if j == index { continue; }
+        state.subset.set(index, j, subset(&state.masks[index], &state.masks[j]));
+        state.subset.set(j, index, subset(&state.masks[j], &state.masks[index]));
+    }
+    patch_changed(&state.masks, &mut state.n1, &mut state.n2, &[index], state.allow_subset_edges, state.min_shared_words);
+    Ok(apply_exclusions(state))
+}
+
+fn check_meta_constraint(rows: &[usize], cols: &[usize], state: &State) -> bool {
     use std::collections::HashMap;
+    if state.soft_meta { return true; }
     let mut counts: HashMap<&str, usize> = HashMap::new();
     for &idx in rows.iter().chain(cols.iter()) {
-        if let Some(ref m) = state.meta_map[idx] {
+        for m in &state.meta_map[idx] {
             let e = counts.entry(m.as_str()).or_insert(0);
             *e += 1;
-            let max_allowed = if *m == "Letter Patterns" { 1 } else { 2 };
-            if *e > max_allowed { return false; }
+            if *e > meta_cap(m, state) { return false; }
         }
     }
     true
 }
 
-fn check_rows_meta(rows: &[usize; 4], state: &State) -> bool {
+fn check_rows_meta(rows: &[usize], state: &State) -> bool {
     use std::collections::HashMap;
+    if state.soft_meta { return true; }
     let mut counts: HashMap<&str, usize> = HashMap::new();
     for &idx in rows.iter() {
-        if let Some(ref m) = state.meta_map[idx] {
+        for m in &state.meta_map[idx] {
             let e = counts.entry(m.as_str()).or_insert(0);
             *e += 1;
-            let max_allowed = if *m == "Letter Patterns" { 1 } else { 2 };
-            if *e > max_allowed { return false; }
+            if *e > meta_cap(m, state) { return false; }
         }
     }
     true
 }
 
-fn excl(rows: &[usize; 4], state: &State) -> bool {
-    // mirrors JS excl
+// Total amount by which the grid's metas exceed their caps (softMeta scoring).
+fn meta_excess(rows: &[usize], cols: &[usize], state: &State) -> usize {
+    use std::collections::HashMap;
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for &idx in rows.iter().chain(cols.iter()) {
+        for m in &state.meta_map[idx] { *counts.entry(m.as_str()).or_insert(0) += 1; }
+    }
+    counts.iter().map(|(m, &n)| n.saturating_sub(meta_cap(m, state))).sum()
+}
+
+fn excl(rows: &[usize], state: &State) -> bool {
+    // mirrors JS excl: every row needs a word none of the other rows has. The other rows'
+    // union is built one limb at a time, so nothing is allocated.
+    if let Some(m) = &state.single {
+        return rows.iter().enumerate().all(|(r, &row)| {
+            let other = rows.iter().enumerate().filter(|&(o, _)| o != r).fold(0u64, |acc, (_, &orow)| acc | m[orow]);
+            m[row] & !other != 0
+        });
+    }
     let mask = &state.masks;
-    let mask_len = mask[0].len();
-    for r in 0..4 {
-        let m = &mask[rows[r]];
-        let mut other = vec![0u32; mask_len];
-        for o in 0..4 {
-            if o == r { continue; }
-            for k in 0..mask_len { other[k] |= mask[rows[o]][k]; }
+    rows.iter().enumerate().all(|(r, &row)| {
+        (0..mask[row].len()).any(|k| {
+            let other = rows.iter().enumerate().filter(|&(o, _)| o != r).fold(0u32, |acc, (_, &orow)| acc | mask[orow][k]);
+            mask[row][k] & !other != 0
+        })
+    })
+}
+
+// Two of the grid's categories with identical masks (mutual subsets) make a degenerate grid.
+fn has_equal_masks(rows: &[usize], cols: &[usize], state: &State) -> bool {
+    let all: Vec<usize> = rows.iter().chain(cols.iter()).copied().collect();
+    all.iter().enumerate().any(|(a, &x)| all[(a + 1)..].iter().any(|&y| state.subset.get(x, y) && state.subset.get(y, x)))
+}
+
+// Clears from `own` every word found in more than `max_share` of the `others` masks.
+fn strip_shared<'a>(own: &mut [u32], others: impl Iterator<Item = &'a [u32]>, max_share: u32, hits: &mut Vec<Vec<u32>>) {
+    if max_share == 0 {
+        for o in others { for (x, &m) in own.iter_mut().zip(o) { *x &= !m; } }
+        return;
+    }
+    // hits[k] = words seen in at least k + 1 of the others so far (bit-sliced counters),
+    // in a caller-owned buffer that is cleared here
+    let t = max_share as usize;
+    hits.resize_with(t + 1, Vec::new);
+    for h in hits.iter_mut() { h.clear(); h.resize(own.len(), 0); }
+    for o in others {
+        for k in (1..=t).rev() {
+            let (lo, hi) = hits.split_at_mut(k);
+            for ((h, &prev), &m) in hi[0].iter_mut().zip(&lo[k - 1]).zip(o) { *h |= prev & m; }
         }
-        let mut ok = false;
-        for k in 0..mask_len {
-            if (m[k] & !other[k]) != 0 { ok = true; break; }
+        for (h, &m) in hits[0].iter_mut().zip(o) { *h |= m; }
+    }
+    for (x, &h) in own.iter_mut().zip(&hits[t]) { *x &= !h; }
+}
+
+// strip_shared for single-limb masks; returns what is left of `own`.
+fn strip_shared1(own: u64, others: impl Iterator<Item = u64>, max_share: u32, hits: &mut Vec<u64>) -> u64 {
+    if max_share == 0 { return others.fold(own, |x, m| x & !m); }
+    let t = max_share as usize;
+    hits.clear();
+    hits.resize(t + 1, 0);
+    for m in others {
+        for k in (1..=t).rev() { hits[k] |= hits[k - 1] & m; }
+        hits[0] |= m;
+    }
+    own & !hits[t]
+}
+
+fn lowest_bit(mask: &[u32]) -> Option<u32> {
+    mask.iter().enumerate().find(|(_, &x)| x != 0).map(|(k, &x)| k as u32 * 32 + x.trailing_zeros())
+}
+
+// Per-cell values, indexed [row][col].
+type CellGrid = Vec<Vec<u32>>;
+
+// A grid that passed every check, with the per-cell results of the uniqueness pass.
+struct Grid {
+    rows: Vec<usize>,
+    cols: Vec<usize>,
+    words: CellGrid,  // lowest unique word bit per cell
+    counts: CellGrid, // unique word count per cell
+    distinct: u32,    // unique words across the whole grid (popcount of the cells' union)
+    weakest_weight: Option<f64>, // with wordWeights: min over cells of the cell's heaviest unique word
+    rarity: Option<f64>,         // with wordCategoryCounts: see Out::Found
+}
+
+// The first cell that failed check_cells: its row and column categories and how many unique
+// words it had (0, or != 1 under uniqueSolution).
+#[derive(Clone, Copy)]
+struct CellFailure {
+    row: usize,
+    col: usize,
+    count: u32,
+}
+
+// A column set that completed the clique walk but failed a final check (explain mode only),
+// or, with empty cols, a row set skipped for exceeding maxCandidateCols (always reported).
+struct Rejected {
+    rows: Vec<usize>,
+    cols: Vec<usize>,
+    reason: String,
+}
+
+// What search_rows does with each completed column set.
+#[derive(Clone, Copy, Default)]
+struct Checks {
+    skip_cells: bool, // skipUniqueness: accept after the meta and equal-mask checks
+    explain: bool,    // pass failures to the callback as Err(Rejected) instead of dropping them
+}
+
+// check_cells' per-cell buffers, one set per thread, reused so the search doesn't allocate
+// for every cell it tests.
+#[derive(Default)]
+struct Scratch {
+    own: Vec<u32>,
+    hits: Vec<Vec<u32>>,
+    hits1: Vec<u64>, // strip_shared1's counters
+}
+
+thread_local! {
+    static SCRATCH: RefCell<Scratch> = RefCell::new(Scratch::default());
+}
+
+// Full uniqueness check: each cell needs a word of its row and column that is (at most
+// max_share times) absent from the other categories. Returns the grid on success.
+fn check_cells(rows: &[usize], cols: &[usize], state: &State) -> Result<Grid, CellFailure> {
+    if let Some(m) = &state.single { return check_cells1(rows, cols, m, state); }
+    let mask_len = state.masks[0].len();
+    let mut words = vec![vec![0u32; cols.len()]; rows.len()];
+    let mut counts = vec![vec![0u32; cols.len()]; rows.len()];
+    let mut union = vec![0u32; mask_len];
+    let weights = state.word_weights.as_deref().filter(|_| state.emit_difficulty);
+    let mut weakest_weight = weights.map(|_| f64::INFINITY);
+    let mut rarity = state.word_counts.as_ref().map(|_| 0.0);
+    let mut all = rows.to_vec(); all.extend_from_slice(cols);
+    SCRATCH.with_borrow_mut(|Scratch { own, hits, .. }| {
+        for (ri, &r) in rows.iter().enumerate() {
+            for (ci, &cc) in cols.iter().enumerate() {
+                own.clear();
+                own.extend((0..mask_len).map(|k| state.masks[r][k] & state.masks[cc][k]));
+                let others = all.iter().filter(|&&o| o != r && o != cc).map(|&o| state.masks[o].as_slice());
+                strip_shared(own, others, state.max_share, hits);
+                counts[ri][ci] = own.iter().map(|x| x.count_ones()).sum();
+                let fail = CellFailure { row: r, col: cc, count: counts[ri][ci] };
+                words[ri][ci] = lowest_bit(own).ok_or(fail)?;
+                if state.unique_solution && counts[ri][ci] != 1 { return Err(fail); }
+                if let (Some(w), Some(weakest)) = (weights, weakest_weight.as_mut()) { *weakest = weakest.min(heaviest_word(own, w)); }
+                if let (Some(c), Some(sum)) = (&state.word_counts, rarity.as_mut()) { *sum += 1.0 / rarest_word(own, c) as f64; }
+                for (u, x) in union.iter_mut().zip(own.iter()) { *u |= x; }
+            }
+        }
+        Ok(())
+    })?;
+    let distinct = union.iter().map(|x| x.count_ones()).sum();
+    let rarity = rarity.map(|sum| sum / (rows.len() * cols.len()) as f64);
+    Ok(Grid { rows: rows.to_vec(), cols: cols.to_vec(), words, counts, distinct, weakest_weight, rarity })
+}
+
+// check_cells on the single-limb masks `m`, with the same results.
+fn check_cells1(rows: &[usize], cols: &[usize], m: &[u64], state: &State) -> Result<Grid, CellFailure> {
+    let mut words = vec![vec![0u32; cols.len()]; rows.len()];
+    let mut counts = vec![vec![0u32; cols.len()]; rows.len()];
+    let mut union = 0u64;
+    let weights = state.word_weights.as_deref().filter(|_| state.emit_difficulty);
+    let mut weakest_weight = weights.map(|_| f64::INFINITY);
+    let mut rarity = state.word_counts.as_ref().map(|_| 0.0);
+    let mut all = rows.to_vec(); all.extend_from_slice(cols);
+    SCRATCH.with_borrow_mut(|Scratch { hits1, .. }| {
+        for (ri, &r) in rows.iter().enumerate() {
+            for (ci, &cc) in cols.iter().enumerate() {
+                let others = all.iter().filter(|&&o| o != r && o != cc).map(|&o| m[o]);
+                let own = strip_shared1(m[r] & m[cc], others, state.max_share, hits1);
+                counts[ri][ci] = own.count_ones();
+                let fail = CellFailure { row: r, col: cc, count: counts[ri][ci] };
+                if own == 0 { return Err(fail); }
+                words[ri][ci] = own.trailing_zeros();
+                if state.unique_solution && counts[ri][ci] != 1 { return Err(fail); }
+                let limbs = [own as u32, (own >> 32) as u32];
+                if let (Some(w), Some(weakest)) = (weights, weakest_weight.as_mut()) { *weakest = weakest.min(heaviest_word(&limbs, w)); }
+                if let (Some(c), Some(sum)) = (&state.word_counts, rarity.as_mut()) { *sum += 1.0 / rarest_word(&limbs, c) as f64; }
+                union |= own;
+            }
+        }
+        Ok(())
+    })?;
+    let rarity = rarity.map(|sum| sum / (rows.len() * cols.len()) as f64);
+    Ok(Grid { rows: rows.to_vec(), cols: cols.to_vec(), words, counts, distinct: union.count_ones(), weakest_weight, rarity })
+}
+
+// Smallest category count among the words set in `mask` (at least 1; bits past the end of
+// `counts` count as 1).
+fn rarest_word(mask: &[u32], counts: &[u32]) -> u32 {
+    let mut rarest = u32::MAX;
+    for (k, &limb) in mask.iter().enumerate() {
+        let mut x = limb;
+        while x != 0 {
+            let bit = k * 32 + x.trailing_zeros() as usize;
+            rarest = rarest.min(counts.get(bit).copied().unwrap_or(1).max(1));
+            x &= x - 1;
         }
-        if !ok { return false; }
+    }
+    rarest
+}
+
+// Largest weight among the words set in `mask`; bits past the end of `weights` weigh 0.
+fn heaviest_word(mask: &[u32], weights: &[f64]) -> f64 {
+    let mut best = 0.0f64;
+    for (k, &limb) in mask.iter().enumerate() {
+        let mut x = limb;
+        while x != 0 {
+            let bit = k * 32 + x.trailing_zeros() as usize;
+            best = best.max(weights.get(bit).copied().unwrap_or(0.0));
+            x &= x - 1;
+        }
+    }
+    best
+}
+
+// Extends `chosen` with ascending members of `cands` until it has `size` entries, each new
+// member n2-adjacent to every entry in chosen[base..]. Calls `f` per completed set; stops
+// and returns false as soon as `f` does.
+fn for_each_clique<F: FnMut(&[usize]) -> bool>(state: &State, cands: &[usize], size: usize, base: usize, chosen: &mut Vec<usize>, f: &mut F) -> bool {
+    if chosen.len() == size { return f(chosen); }
+    let need = size - chosen.len();
+    for (a, &x) in cands.iter().enumerate() {
+        if cands.len() - a < need { break; }
+        if !chosen[base..].iter().all(|&p| state.n2[p].binary_search(&x).is_ok()) { continue; }
+        chosen.push(x);
+        let go = for_each_clique(state, &cands[(a + 1)..], size, base, chosen, f);
+        chosen.pop();
+        if !go { return false; }
     }
     true
 }
 
-fn run_work_streaming<W: Write>(state: &State, start: usize, end: usize, j_start: Option<usize>, j_end: Option<usize>, writer: &mut W) {
-    let _n = state.masks.len();
-    let mask_len = state.masks[0].len();
+// Searches every column set for a fixed row set, calling `f` on each valid grid.
+// `f` returns false to stop; the search then returns false as well. With `break_symmetry`,
+// square grids only take columns above rows[0] (the transpose is found from the other side).
+fn search_rows<F: FnMut(Result<Grid, Rejected>) -> bool>(state: &State, rows: &[usize], break_symmetry: bool, checks: Checks, f: &mut F) -> bool {
+    if !excl(rows, state) { return true; }
+    if !check_rows_meta(rows, state) { return true; }
+
+    // column candidates
+    let mut cand: Vec<usize> = state.n1[rows[0]].clone();
+    cand.sort_unstable();
+    for &row in &rows[1..] {
+        let nr = &state.n1[row];
+        let mut tmp = Vec::with_capacity(cand.len());
+        let mut a=0usize; let mut b=0usize;
+        let mut sorted_nr = nr.clone();
+        sorted_nr.sort_unstable();
+        while a < cand.len() && b < sorted_nr.len() {
+            if cand[a] == sorted_nr[b] { tmp.push(cand[a]); a+=1; b+=1; }
+            else if cand[a] < sorted_nr[b] { a+=1; } else { b+=1; }
+        }
+        cand = tmp;
+    }
+    cand.retain(|&c| !state.excluded[c] && !rows.contains(&c));
+    // filter by subset matrix like JS: remove c if any S[r][c] is true
+    if !state.allow_subset_edges {
+        cand.retain(|&c| !rows.iter().any(|&r| state.subset.get(r, c)));
+    }
+    if cand.len() < state.cols_n { return true; }
+    if break_symmetry && state.rows_n == state.cols_n && cand.iter().min().copied().unwrap_or(usize::MAX) <= rows[0] { return true; }
+    if cand.len() > state.max_candidate_cols {
+        // skipped rather than truncated, so no grid is ever reported from a partial walk
+        let reason = format!("skipped rows {:?}: {} candidate columns exceed maxCandidateCols {}", rows, cand.len(), state.max_candidate_cols);
+        return f(Err(Rejected { rows: rows.to_vec(), cols: Vec::new(), reason }));
+    }
+
+    // Found lists rows and cols ascending, however the rows were supplied (cols come out of
+    // the clique walk ascending already)
+    let mut sorted_rows = rows.to_vec();
+    sorted_rows.sort_unstable();
+    let rows = sorted_rows.as_slice();
+    let mut c_arr = cand.clone();
+    c_arr.sort_unstable();
+    let mut cols = Vec::with_capacity(state.cols_n);
+    let Some(pool) = &state.pool else {
+        return for_each_clique(state, &c_arr, state.cols_n, 0, &mut cols, &mut |cols: &[usize]| {
+            finish_cols(state, rows, cols, checks).is_none_or(&mut *f)
+        });
+    };
+    // threads > 1: gather up to PAR_CHUNK column sets, check them in parallel, report them in
+    // walk order, and carry on walking
+    let flush = |chunk: &mut Vec<Vec<usize>>, f: &mut F| -> bool {
+        let results: Vec<Option<Result<Grid, Rejected>>> = pool.install(|| chunk.par_iter().map(|cols| finish_cols(state, rows, cols, checks)).collect());
+        chunk.clear();
+        results.into_iter().flatten().all(f)
+    };
+    let mut chunk: Vec<Vec<usize>> = Vec::with_capacity(PAR_CHUNK);
+    let go = for_each_clique(state, &c_arr, state.cols_n, 0, &mut cols, &mut |cols: &[usize]| {
+        chunk.push(cols.to_vec());
+        chunk.len() < PAR_CHUNK || flush(&mut chunk, f)
+    });
+    go && flush(&mut chunk, f)
+}
+
+// Final checks for one column set. None means it failed and explain mode is off.
+fn finish_cols(state: &State, rows: &[usize], cols: &[usize], checks: Checks) -> Option<Result<Grid, Rejected>> {
+    let reject = |reason: String| if checks.explain { Some(Err(Rejected { rows: rows.to_vec(), cols: cols.to_vec(), reason })) } else { None };
+    // meta constraint full set
+    if !check_meta_constraint(rows, cols, state) { return reject("meta-category cap exceeded".into()); }
+    if has_equal_masks(rows, cols, state) { return reject("two categories have identical word sets".into()); }
+
+    // fast mode leaves words/counts empty; the cleaner filters false positives later
+    if checks.skip_cells { return Some(Ok(Grid { rows: rows.to_vec(), cols: cols.to_vec(), words: Vec::new(), counts: Vec::new(), distinct: 0, weakest_weight: None, rarity: None })); }
+    match check_cells(rows, cols, state) {
+        Ok(g) => Some(Ok(g)),
+        Err(e) if e.count == 0 => reject(format!("cell ({}, {}) has no unique word", e.row, e.col)),
+        Err(e) => reject(format!("cell ({}, {}) has {} unique words but uniqueSolution needs 1", e.row, e.col, e.count)),
+    }
+}
+
+// Expands (i, j) into row sets i < j < k < ... along n2 and searches each of them.
+fn search_ij<F: FnMut(Result<Grid, Rejected>) -> bool>(state: &State, i: usize, j: usize, checks: Checks, f: &mut F) -> bool {
+    // Build k list
+    let mut k_list: Vec<usize> = state.n2[i].iter().copied().filter(|&k| k > j && !state.excluded[k] && state.n2[j].binary_search(&k).is_ok()).collect();
+    // note: n2[j] not guaranteed sorted, ensure sorted once
+    k_list.sort_unstable();
+
+    let mut rows = vec![i, j];
+    for_each_clique(state, &k_list, state.rows_n, 2, &mut rows, &mut |rows: &[usize]| search_rows(state, rows, true, checks, f))
+}
+
+// Sorted j's with j > i along n2 — the second row candidates for first row i (none when i
+// itself is excluded).
+fn j_candidates(state: &State, i: usize) -> Vec<usize> {
+    if state.excluded[i] { return Vec::new(); }
+    let mut j_list: Vec<usize> = state.n2[i].iter().copied().filter(|&j| j > i && !state.excluded[j]).collect();
+    j_list.sort_unstable();
+    j_list
+}
+
+// Checks a caller-supplied row set before completing it; Err explains why it can't work.
+fn check_fixed_rows(state: &State, rows: &[usize]) -> Result<(), String> {
+    if rows.len() != state.rows_n { return Err(format!("expected {} rows, got {}", state.rows_n, rows.len())); }
+    if let Some(&r) = rows.iter().find(|&&r| r >= state.masks.len()) { return Err(format!("row index {} out of range", r)); }
+    if rows.iter().enumerate().any(|(a, r)| rows[(a + 1)..].contains(r)) { return Err("rows contain a duplicate category".into()); }
+    if let Some(&r) = rows.iter().find(|&&r| state.excluded[r]) { return Err(format!("row {} is excluded", r)); }
+    if !excl(rows, state) { return Err("rows fail excl: some row has no word outside the other rows".into()); }
+    if !check_rows_meta(rows, state) { return Err("rows exceed a meta-category limit".into()); }
+    Ok(())
+}
+
+// Outside explain mode the only Err a search passes on is a row set skipped for
+// maxCandidateCols; it is reported as a Warning and the search moves on to the next row set.
+fn warn_skipped<W: Write>(r: Rejected, enc: Encoding, writer: &mut W) {
+    wire::send(writer, enc, &Out::Warning { message: r.reason });
+}
+
+// Scans from a random (i, j) starting point, wrapping around, and stops at the first valid grid.
+fn find_one<W: Write>(state: &State, seed: Option<u64>, enc: Encoding, writer: &mut W) -> Option<Grid> {
+    let mut rng = seed.map(SplitMix64::new).unwrap_or_else(SplitMix64::from_time);
+    let n = state.masks.len();
+    if n == 0 { return None; }
+    let i0 = rng.below(n);
+    for t in 0..n {
+        let i = (i0 + t) % n;
+        let j_list = j_candidates(state, i);
+        if j_list.is_empty() { continue; }
+        let j0 = rng.below(j_list.len());
+        for u in 0..j_list.len() {
+            let j = j_list[(j0 + u) % j_list.len()];
+            let mut hit = None;
+            search_ij(state, i, j, Checks::default(), &mut |g| match g {
+                Ok(g) => { hit = Some(g); false }
+                Err(r) => { warn_skipped(r, enc, writer); true }
+            });
+            if hit.is_some() { return hit; }
+        }
+    }
+    None
+}
+
+// Fourth rows that n2-connect to all of `rows`, ascending; Err if the three rows can't share a
+// grid in the first place.
+fn fourth_rows(state: &State, rows: [usize; 3]) -> Result<Vec<usize>, String> {
+    if state.rows_n != 4 { return Err(format!("CompleteThreeRows needs rows_n = 4, not {}", state.rows_n)); }
+    if let Some(&r) = rows.iter().find(|&&r| r >= state.masks.len()) { return Err(format!("row index {} out of range", r)); }
+    if let Some(&r) = rows.iter().find(|&&r| state.excluded[r]) { return Err(format!("row {} is excluded", r)); }
+    let linked = |a: usize, b: usize| state.n2[a].binary_search(&b).is_ok();
+    for (a, b) in [(0, 1), (0, 2), (1, 2)] {
+        if rows[a] == rows[b] { return Err("rows contain a duplicate category".into()); }
+        if !linked(rows[a], rows[b]) { return Err(format!("rows {} and {} are not n2-connected", rows[a], rows[b])); }
+    }
+    Ok(state.n2[rows[0]].iter().copied().filter(|&x| !state.excluded[x] && linked(rows[1], x) && linked(rows[2], x)).collect())
+}
+
+// Reservoir sample (Algorithm R) of k grids over every i in start..end, in the order the
+// slots hold them, plus how many grids were seen. The search visits each grid once (rows
+// ascending, symmetry broken on the smallest index), so they are all distinct.
+fn sample<W: Write>(state: &State, start: usize, end: usize, k: usize, seed: u64, enc: Encoding, writer: &mut W) -> (Vec<Grid>, usize) {
+    let mut rng = SplitMix64::new(seed);
+    let mut total = 0usize;
+    let mut reservoir: Vec<Grid> = Vec::with_capacity(k.min(1024)); // k is caller-supplied; grow as grids turn up
+    for i in start..end.min(state.masks.len()) {
+        for j in j_candidates(state, i) {
+            search_ij(state, i, j, Checks::default(), &mut |g| {
+                let g = match g {
+                    Ok(g) => g,
+                    Err(r) => { warn_skipped(r, enc, writer); return true; }
+                };
+                total += 1;
+                if reservoir.len() < k {
+                    reservoir.push(g);
+                } else {
+                    let slot = rng.below(total);
+                    if slot < k { reservoir[slot] = g; }
+                }
+                true
+            });
+        }
+    }
+    (reservoir, total)
+}
+
+fn emit_found<W: Write>(state: &State, g: &Grid, enc: Encoding, writer: &mut W) {
+    if state.compact_found {
+        // Init and AddCategories keep every index within u16; should one slip past, the grid
+        // goes out as a full Found rather than with truncated indices
+        let packed: Option<Vec<[u8; 2]>> = g.rows.iter().chain(g.cols.iter()).map(|&x| u16::try_from(x).ok().map(u16::to_le_bytes)).collect();
+        if let Some(bytes) = packed {
+            wire::send(writer, enc, &Out::Packed { g: BASE64.encode(bytes.concat()) });
+            return;
+        }
+    }
+    let checked = !g.words.is_empty(); // false for skipUniqueness grids, which have no cell data
+    let words = if state.emit_words && checked { Some(g.words.clone()) } else { None };
+    let cell_counts = if state.emit_cell_counts && checked { Some(g.counts.clone()) } else { None };
+    let distinct_words = if state.emit_distinct_words && checked { Some(g.distinct) } else { None };
+    let hardest_cells = if state.hardest_cells > 0 && checked { Some(hardest_cells(&g.counts, state.hardest_cells)) } else { None };
+    let connectivity = if state.emit_connectivity { Some(connectivity(&g.rows, &g.cols, state)) } else { None };
+    let puzzle_hash = if state.emit_puzzle_hash {
+        let names = |side: &[usize]| side.iter().map(|&x| state.categories[x].as_str()).collect::<Vec<&str>>();
+        Some(puzzle_hash(&names(&g.rows), &names(&g.cols)))
+    } else { None };
+    let difficulty = if state.emit_difficulty && checked {
+        Some(g.weakest_weight.unwrap_or_else(|| g.counts.iter().flatten().copied().min().unwrap_or(0) as f64))
+    } else { None };
+    let score = if state.soft_meta { Some(0.0 - meta_excess(&g.rows, &g.cols, state) as f64 * state.meta_penalty) } else { None };
+    wire::send(writer, enc, &Out::Found { rows: g.rows.clone(), cols: g.cols.clone(), words, cell_counts, distinctWords: distinct_words, hardestCells: hardest_cells, connectivity, puzzle_hash, difficulty, rarity: g.rarity, score });
+}
+
+// Sum of the 2-hop path counts (shared n1 neighbours, the N2 criterion) over every row pair
+// and every column pair.
+fn connectivity(rows: &[usize], cols: &[usize], state: &State) -> u32 {
+    let pairs = |side: &[usize]| -> usize {
+        side.iter().enumerate().map(|(a, &x)| side[(a + 1)..].iter().map(|&y| count_common(&state.n1[x], &state.n1[y])).sum::<usize>()).sum()
+    };
+    (pairs(rows) + pairs(cols)) as u32
+}
+
+// The k cells with the fewest unique words as (row, col) positions, fewest first (ties in
+// row-major order).
+fn hardest_cells(counts: &CellGrid, k: usize) -> Vec<(usize, usize)> {
+    let mut cells: Vec<(u32, usize, usize)> = counts.iter().enumerate()
+        .flat_map(|(r, row)| row.iter().enumerate().map(move |(c, &n)| (n, r, c)))
+        .collect();
+    cells.sort_unstable();
+    cells.into_iter().take(k).map(|(_, r, c)| (r, c)).collect()
+}
 
-    let mut found_count: usize = 0;
-    for i in start..end {
-        let mut j_list: Vec<usize> = state.n2[i].iter().copied().filter(|&j| j > i).collect();
-        j_list.sort_unstable();
+// Per-run aggregates over the emitted grids, filled only when Init asked for them.
+#[derive(Default)]
+struct Tallies {
+    metas: BTreeMap<String, usize>,         // metaReport
+    pairs: BTreeMap<(usize, usize), usize>, // cooccurrence, keyed a < b
+}
+
+impl Tallies {
+    fn add(&mut self, rows: &[usize], cols: &[usize], state: &State) {
+        if state.meta_report {
+            // once per meta, however many of the grid's categories share it
+            let metas: BTreeSet<&String> = rows.iter().chain(cols.iter()).flat_map(|&x| state.meta_map[x].iter()).collect();
+            for m in metas { *self.metas.entry(m.clone()).or_insert(0) += 1; }
+        }
+        if state.cooccurrence {
+            let mut all = rows.to_vec(); all.extend_from_slice(cols);
+            all.sort_unstable();
+            for (a, &x) in all.iter().enumerate() {
+                for &y in &all[(a + 1)..] { *self.pairs.entry((x, y)).or_insert(0) += 1; }
+            }
+        }
+    }
+
+    fn send<W: Write>(self, state: &State, enc: Encoding, writer: &mut W) {
+        if state.meta_report { wire::send(writer, enc, &Out::MetaReport { counts: self.metas.into_iter().collect() }); }
+        if state.cooccurrence { wire::send(writer, enc, &Out::Cooccurrence { pairs: self.pairs.into_iter().map(|((a, b), n)| (a, b, n)).collect() }); }
+    }
+}
+
+// `tallies` accumulates across calls, so a WorkBatch reports once.
+fn run_work_streaming<W: Write>(state: &State, work: &WorkParams, enc: Encoding, writer: &mut W, tallies: &mut Tallies) {
+    let mut found_count = 0usize;
+    let checks = Checks { skip_cells: work.skip_uniqueness, explain: work.explain };
+    let mut rng = work.seed.map(SplitMix64::new);
+    let mut i_order: Vec<usize> = (work.start..work.end).collect();
+    if let Some(ref mut r) = rng { r.shuffle(&mut i_order); }
+    let range: WorkRange = (work.start, work.end, work.j_start, work.j_end, work.seed, work.shuffle_j);
+    let (resume_i, resume_j) = work.checkpoint_path.as_deref().map_or((0, 0), |p| load_checkpoint(p, range));
+    let mut checkpoint = work.checkpoint_path.as_deref();
+
+    for (pos, i) in i_order.into_iter().enumerate() {
+        let started = std::time::Instant::now();
+        let mut found_i = 0usize;
+        let mut rejected_i = 0usize;
+        let j_list = j_candidates(state, i);
 
         let mut total_j = j_list.len();
         let (mut ps, mut pe) = (0usize, total_j);
-        if let (Some(s), Some(e)) = (j_start, j_end) {
+        if let (Some(s), Some(e)) = (work.j_start, work.j_end) {
             let s = s.min(total_j);
             let e = e.min(total_j).max(s);
             total_j = e - s;
             ps = s; pe = e;
         }
         let mut j_progress = 0usize;
+        let mut j_slice = j_list[ps..pe].to_vec();
+        if let (Some(ref mut r), true) = (&mut rng, work.shuffle_j) { r.shuffle(&mut j_slice); }
+        // j's a checkpoint says are already done (shuffling above still ran, keeping rng in step)
+        let skip = if pos < resume_i { total_j } else if pos == resume_i { resume_j.min(total_j) } else { 0 };
+        j_progress += skip;
 
-        for jj in ps..pe {
-            let j = j_list[jj];
-
-            // Build k list
-            let mut k_list: Vec<usize> = state.n2[i].iter().copied().filter(|&k| k > j && state.n2[j].binary_search(&k).is_ok()).collect();
-            // note: n2[j] not guaranteed sorted, ensure sorted once
-            k_list.sort_unstable();
-
-            for &k in &k_list {
-                // l list
-                let mut l_list: Vec<usize> = k_list.iter().copied().filter(|&l| l > k && state.n2[k].binary_search(&l).is_ok()).collect();
-                l_list.sort_unstable();
-                for &l in &l_list {
-                    let rows = [i, j, k, l];
-                    if !excl(&rows, state) { continue; }
-                    if !check_rows_meta(&rows, state) { continue; }
-
-                    // meta constraint rows
-                    if !check_meta_constraint(&rows, &[0,0,0,0], state) { /* cols unknown here; handled later as full set */ }
-
-                    // column candidates
-                    let mut cand: Vec<usize> = state.n1[i].clone();
-                    cand.sort_unstable();
-                    for r in 1..4 {
-                        let nr = &state.n1[rows[r]];
-                        let mut tmp = Vec::with_capacity(cand.len());
-                        let mut a=0usize; let mut b=0usize;
-                        let mut sorted_nr = nr.clone();
-                        sorted_nr.sort_unstable();
-                        while a < cand.len() && b < sorted_nr.len() {
-                            if cand[a] == sorted_nr[b] { tmp.push(cand[a]); a+=1; b+=1; }
-                            else if cand[a] < sorted_nr[b] { a+=1; } else { b+=1; }
-                        }
-                        cand = tmp;
+        for j in j_slice.into_iter().skip(skip) {
+            search_ij(state, i, j, checks, &mut |g| {
+                let g = match g {
+                    Ok(g) => g,
+                    Err(r) if r.cols.is_empty() => {
+                        warn_skipped(r, enc, writer);
+                        return true;
                     }
-                    cand.retain(|c| !rows.iter().any(|r| r == c));
-                    // filter by subset matrix like JS: remove c if any S[r][c] is true
-                    cand.retain(|&c| !rows.iter().any(|&r| state.subset[r][c]));
-                    if cand.len() < 4 || cand.iter().min().copied().unwrap_or(usize::MAX) <= rows[0] { continue; }
-
-                    let mut c_arr = cand.clone();
-                    c_arr.sort_unstable();
-                    let m = c_arr.len();
-                    for a in 0..m.saturating_sub(3) {
-                        for b in (a+1)..m.saturating_sub(2) {
-                            let x = c_arr[a]; let y = c_arr[b];
-                            if !state.n2[x].binary_search(&y).is_ok() { continue; }
-                            for c in (b+1)..m.saturating_sub(1) {
-                                let z = c_arr[c];
-                                if !(state.n2[x].binary_search(&z).is_ok() && state.n2[y].binary_search(&z).is_ok()) { continue; }
-                                for d in (c+1)..m {
-                                    let w = c_arr[d];
-                                    if !(state.n2[x].binary_search(&w).is_ok() && state.n2[y].binary_search(&w).is_ok() && state.n2[z].binary_search(&w).is_ok()) { continue; }
-                                    let cols = [x,y,z,w];
-
-                                    // meta constraint full set
-                                    if !check_meta_constraint(&rows, &cols, state) { continue; }
-
-                                    // full uniqueness check
-                                    let mut ok = true;
-                                    let mut all = rows.to_vec(); all.extend_from_slice(&cols);
-                                    for &r in &rows {
-                                        for &cc in &cols {
-                                            let mut own: Vec<u32> = (0..mask_len).map(|k| state.masks[r][k] & state.masks[cc][k]).collect();
-                                            for &o in &all { if o != r && o != cc { for k in 0..mask_len { own[k] &= !state.masks[o][k]; } } }
-                                            if !own.iter().any(|&x| x != 0) { ok = false; break; }
-                                        }
-                                        if !ok { break; }
-                                    }
-                                    if !ok { continue; }
-
-                                    let _ = writeln!(writer, "{}", serde_json::to_string(&Out::Found { rows, cols }).unwrap());
-                                    found_count += 1;
-                                }
-                            }
+                    Err(r) => {
+                        if rejected_i < EXPLAIN_PER_I {
+                            rejected_i += 1;
+                            wire::send(writer, enc, &Out::Rejected { rows: r.rows, cols: r.cols, reason: r.reason });
                         }
+                        return true;
                     }
-                }
-            }
+                };
+                emit_found(state, &g, enc, writer);
+                tallies.add(&g.rows, &g.cols, state);
+                found_i += 1;
+                true
+            });
             j_progress += 1;
-            if j_progress % 2 == 0 || j_progress == total_j {
-                let _ = writeln!(writer, "{}", serde_json::to_string(&Out::Tick { jProgress: j_progress, totalJ: total_j }).unwrap());
+            if (state.tick_every > 0 && j_progress.is_multiple_of(state.tick_every)) || j_progress == total_j {
+                save_progress(&mut checkpoint, Checkpoint { range, i_done: pos, j_done: j_progress }, enc, writer);
+                wire::send(writer, enc, &Out::Tick { jProgress: j_progress, totalJ: total_j, foundSoFar: found_count + found_i });
             }
         }
-        if total_j == 0 || j_progress != total_j {
-            let _ = writeln!(writer, "{}", serde_json::to_string(&Out::Tick { jProgress: total_j, totalJ: total_j }).unwrap());
+        if pos >= resume_i { save_progress(&mut checkpoint, Checkpoint { range, i_done: pos + 1, j_done: 0 }, enc, writer); }
+        if total_j == 0 || j_progress != total_j || skip == total_j {
+            wire::send(writer, enc, &Out::Tick { jProgress: total_j, totalJ: total_j, foundSoFar: found_count + found_i });
+        }
+        found_count += found_i;
+        if state.profile {
+            wire::send(writer, enc, &Out::ITiming { i, elapsed_ms: started.elapsed().as_millis() as u64, found: found_i });
         }
     }
-    let _ = writeln!(writer, "{}", serde_json::to_string(&Out::Done { totalJ: 0 }).unwrap());
+    wire::send(writer, enc, &Out::Done { totalJ: 0 });
+}
+
+// Runs the ranges in order, asking `cancelled` before each one; stops at the first yes.
+// Reports once for the whole batch, then BatchDone with the ranges that ran to completion.
+fn run_batch<W: Write, C: FnMut() -> bool>(state: &State, ranges: &[(usize, usize, Option<usize>, Option<usize>)], enc: Encoding, writer: &mut W, cancelled: &mut C) {
+    let mut tallies = Tallies::default();
+    let mut done = 0usize;
+    for &(start, end, j_start, j_end) in ranges {
+        if cancelled() { break; }
+        let work = WorkParams { start, end, j_start, j_end, seed: None, shuffle_j: false, skip_uniqueness: false, explain: false, checkpoint_path: None };
+        run_work_streaming(state, &work, enc, writer, &mut tallies);
+        done += 1;
+    }
+    tallies.send(state, enc, writer);
+    wire::send(writer, enc, &Out::BatchDone { ranges: done });
+}
+
+// Reads stdin on its own thread, so a WorkBatch can look for Cancel between ranges while the
+// main loop is busy. The channel closes at EOF.
+fn spawn_reader(enc: Encoding) -> mpsc::Receiver<Result<Msg, String>> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(std::io::stdin().lock());
+        let mut line = Vec::new();
+        while let Some(m) = wire::read_msg(&mut reader, enc, &mut line) {
+            if tx.send(m).is_err() { break; }
+        }
+    });
+    rx
 }
 
 fn main() {
-    let stdin = std::io::stdin();
-    let mut reader = BufReader::new(stdin.lock());
-    let mut line = String::new();
+    let enc = Encoding::from_env();
+    let inbox = spawn_reader(enc);
+    let mut pending: VecDeque<Result<Msg, String>> = VecDeque::new(); // read while a WorkBatch ran
     let mut state_opt: Option<State> = None;
     let mut stdout = std::io::stdout();
 
     loop {
-        line.clear();
-        let n = reader.read_line(&mut line).unwrap();
-        if n == 0 { break; }
-        let msg: Msg = match serde_json::from_str(&line) {
+        let next = match pending.pop_front() {
+            Some(m) => m,
+            None => match inbox.recv() { Ok(m) => m, Err(_) => break },
+        };
+        let msg: Msg = match next {
             Ok(m) => m,
-            Err(e) => { let _ = writeln!(stdout, "{}", serde_json::to_string(&Out::Error{ message: format!("bad json: {}", e)}).unwrap()); continue; }
+            Err(e) => { wire::send(&mut stdout, enc, &Out::Error{ message: e }); continue; }
         };
         match msg {
-            Msg::Init { masks, mut n1, mut n2, categories, meta_map } => {
+            Msg::Init {
+                masks,
+                limb_bits,
+                mut n1,
+                mut n2,
+                mut categories,
+                mut meta_map,
+                adjacency_path,
+                cache_path,
+                word_list_hash,
+                verifyWordListHash,
+                words_path,
+                emitWords,
+                emitCellCounts,
+                emitDistinctWords,
+                hardestCells,
+                emitConnectivity,
+                emitPuzzleHash,
+                metaReport,
+                cooccurrence,
+                emitDifficulty,
+                wordWeights,
+                wordCategoryCounts,
+                uniqueSolution,
+                distinctMeta,
+                softMeta,
+                metaPenalty,
+                maxShare,
+                tickEvery,
+                minCategorySize,
+                maxCategorySize,
+                excludeMetas,
+                maxN2Degree,
+                maxCandidateCols,
+                allowSubsetEdges,
+                minSharedWords,
+                profile,
+                compactFound,
+                maxMemBytes,
+                threads,
+                rows_n,
+                cols_n,
+            } => {
+                let (rows_n, cols_n) = (rows_n.unwrap_or(4), cols_n.unwrap_or(4));
+                if rows_n < 2 || cols_n < 2 {
+                    wire::send(&mut stdout, enc, &Out::Error{ message: format!("grid shape {}x{} not supported (rows_n and cols_n must be >= 2)", rows_n, cols_n) });
+                    continue;
+                }
+                let limb_bits = limb_bits.unwrap_or(32);
+                let mut masks = match repack_limbs(masks, limb_bits) {
+                    Ok(m) => m,
+                    Err(message) => { wire::send(&mut stdout, enc, &Out::Error{ message }); continue; }
+                };
+                let mut build_here = false;
+                if let Some(path) = cache_path {
+                    let b = match read_bundle(&path) {
+                        Ok(b) => b,
+                        Err(message) => { wire::send(&mut stdout, enc, &Out::Error{ message }); continue; }
+                    };
+                    if let Some(hash) = word_list_hash.as_ref().filter(|h| **h != b.word_list_hash) {
+                        wire::send(&mut stdout, enc, &Out::Error{ message: format!("bundle is stale: built for word_list_hash {} but Init has {}", b.word_list_hash, hash) });
+                        continue;
+                    }
+                    (masks, n1, n2, categories, meta_map) = (b.masks, b.n1, b.n2, b.categories, b.meta_map.into_iter().map(MetaTags::Many).collect());
+                } else if let Some(path) = adjacency_path {
+                    let Some(hash) = &word_list_hash else {
+                        wire::send(&mut stdout, enc, &Out::Error{ message: "adjacency_path requires word_list_hash".into() });
+                        continue;
+                    };
+                    match read_adjacency(&path, hash) {
+                        Ok((c1, c2)) => { n1 = c1; n2 = c2; }
+                        Err(message) => { wire::send(&mut stdout, enc, &Out::Error{ message }); continue; }
+                    }
+                    if n1.len() != masks.len() {
+                        wire::send(&mut stdout, enc, &Out::Error{ message: format!("adjacency cache has {} categories but Init has {}", n1.len(), masks.len()) });
+                        continue;
+                    }
+                } else if n1.is_empty() && n2.is_empty() && !masks.is_empty() {
+                    // no adjacency supplied: built below, once the memory budget allows it
+                    build_here = true;
+                }
+                // the subset matrix, plus build's n × n shared-neighbour counts when it runs here,
+                // must fit the memory budget
+                let budget = maxMemBytes.unwrap_or(DEFAULT_MAX_MEM_BYTES);
+                let needed = if build_here { build_bytes(masks.len()) } else { BitMatrix::bytes_for(masks.len()) };
+                if needed > budget {
+                    let what = if build_here { "subset matrix and adjacency build" } else { "subset matrix" };
+                    wire::send(&mut stdout, enc, &Out::Error{ message: format!("{} for {} categories needs {} bytes, over the maxMemBytes budget of {}", what, masks.len(), needed, budget) });
+                    continue;
+                }
+                if build_here {
+                    // exactly as cdx_helper would
+                    (n1, n2) = build(&masks, allowSubsetEdges.unwrap_or(false), minSharedWords.unwrap_or(0));
+                }
+                let meta_map: Vec<Vec<String>> = meta_map.into_iter().map(Vec::from).collect();
+                if let Err(message) = check_init_lengths(&masks, &n1, &n2, &categories, &meta_map) {
+                    wire::send(&mut stdout, enc, &Out::Error{ message });
+                    continue;
+                }
+                if verifyWordListHash.unwrap_or(false) {
+                    let Some(path) = &words_path else {
+                        wire::send(&mut stdout, enc, &Out::Error{ message: "verifyWordListHash requires words_path".into() });
+                        continue;
+                    };
+                    let actual = match std::fs::read(path) {
+                        Ok(bytes) => hash::word_list_hash(&bytes),
+                        Err(e) => { wire::send(&mut stdout, enc, &Out::Error{ message: format!("cannot read {}: {}", path, e) }); continue; }
+                    };
+                    if word_list_hash.as_deref() != Some(actual.as_str()) {
+                        wire::send(&mut stdout, enc, &Out::Error{ message: format!("word_list_hash {} does not match {} (expected {})", word_list_hash.as_deref().unwrap_or("(missing)"), path, actual) });
+                        continue;
+                    }
+                }
                 // sort adjacency for binary_search
                 for v in &mut n1 { v.sort_unstable(); }
                 for v in &mut n2 { v.sort_unstable(); }
-                // compute subset matrix S
-                let ncat = masks.len();
-                let mut subset = vec![vec![false; ncat]; ncat];
-                for i in 0..ncat {
-                    for j in 0..ncat {
-                        if i==j { continue; }
-                        let a_sub_b = masks[i].iter().zip(&masks[j]).all(|(a,b)| (a & !b) == 0);
-                        if a_sub_b { subset[i][j] = true; }
+                if compactFound.unwrap_or(false) && masks.len() > u16::MAX as usize + 1 {
+                    wire::send(&mut stdout, enc, &Out::Error{ message: format!("compactFound packs indices as u16, but there are {} categories", masks.len()) });
+                    continue;
+                }
+                // compute subset matrix S (within the budget checked above)
+                let subset = subset_matrix(&masks, false);
+                let single = pack_single(&masks);
+                let mut state = State {
+                    masks,
+                    single,
+                    limb_bits,
+                    n1,
+                    n2,
+                    categories,
+                    meta_map,
+                    subset,
+                    emit_words: emitWords.unwrap_or(false),
+                    emit_cell_counts: emitCellCounts.unwrap_or(false),
+                    emit_distinct_words: emitDistinctWords.unwrap_or(false),
+                    hardest_cells: hardestCells.unwrap_or(0),
+                    emit_connectivity: emitConnectivity.unwrap_or(false),
+                    emit_puzzle_hash: emitPuzzleHash.unwrap_or(false),
+                    emit_difficulty: emitDifficulty.unwrap_or(false),
+                    meta_report: metaReport.unwrap_or(false),
+                    cooccurrence: cooccurrence.unwrap_or(false),
+                    word_weights: wordWeights,
+                    word_counts: wordCategoryCounts,
+                    max_share: maxShare.unwrap_or(0),
+                    unique_solution: uniqueSolution.unwrap_or(false),
+                    soft_meta: softMeta.unwrap_or(false),
+                    distinct_meta: distinctMeta.unwrap_or(false),
+                    meta_penalty: metaPenalty.unwrap_or(1.0),
+                    tick_every: tickEvery.unwrap_or(2),
+                    rows_n,
+                    cols_n,
+                    profile: profile.unwrap_or(false),
+                    compact_found: compactFound.unwrap_or(false),
+                    max_mem_bytes: budget,
+                    size_range: (minCategorySize.unwrap_or(0), maxCategorySize.unwrap_or(u32::MAX)),
+                    max_n2_degree: maxN2Degree.unwrap_or(usize::MAX),
+                    max_candidate_cols: maxCandidateCols.unwrap_or(usize::MAX),
+                    allow_subset_edges: allowSubsetEdges.unwrap_or(false),
+                    min_shared_words: minSharedWords.unwrap_or(0),
+                    exclude_metas: excludeMetas,
+                    excluded: Vec::new(),
+                    pool: None,
+                };
+                if let Some(t) = threads.filter(|&t| t > 1) {
+                    match rayon::ThreadPoolBuilder::new().num_threads(t).build() {
+                        Ok(p) => state.pool = Some(p),
+                        Err(e) => { wire::send(&mut stdout, enc, &Out::Error{ message: format!("cannot start {} threads: {}", t, e) }); continue; }
+                    }
+                }
+                let hubs = apply_exclusions(&mut state);
+                if !state.exclude_metas.is_empty() {
+                    let removed = (0..state.masks.len()).filter(|&i| has_excluded_meta(i, &state)).count();
+                    wire::send(&mut stdout, enc, &Out::MetasExcluded{ removed });
+                }
+                if !hubs.is_empty() { wire::send(&mut stdout, enc, &Out::HubsExcluded{ categories: hubs }); }
+                state_opt = Some(state);
+                wire::send(&mut stdout, enc, &Out::Ready);
+            }
+            Msg::AddCategories { masks, categories, meta_map } => {
+                if let Some(ref mut state) = state_opt {
+                    match add_categories(state, masks, categories, meta_map) {
+                        Ok((added, hubs)) => {
+                            if !hubs.is_empty() { wire::send(&mut stdout, enc, &Out::HubsExcluded{ categories: hubs }); }
+                            wire::send(&mut stdout, enc, &Out::Added{ added, total: state.masks.len() });
+                        }
+                        Err(message) => { wire::send(&mut stdout, enc, &Out::Error{ message }); }
+                    }
+                } else {
+                    wire::send(&mut stdout, enc, &Out::Error{ message: "not initialized".into()});
+                }
+            }
+            Msg::UpdateMask { index, mask } => {
+                if let Some(ref mut state) = state_opt {
+                    match update_mask(state, index, mask) {
+                        Ok(hubs) => {
+                            if !hubs.is_empty() { wire::send(&mut stdout, enc, &Out::HubsExcluded{ categories: hubs }); }
+                            wire::send(&mut stdout, enc, &Out::Updated{ index });
+                        }
+                        Err(message) => { wire::send(&mut stdout, enc, &Out::Error{ message }); }
+                    }
+                } else {
+                    wire::send(&mut stdout, enc, &Out::Error{ message: "not initialized".into()});
+                }
+            }
+            Msg::CompleteRows { rows } => {
+                if let Some(ref state) = state_opt {
+                    if let Err(message) = check_fixed_rows(state, &rows) {
+                        wire::send(&mut stdout, enc, &Out::Error{ message });
+                        continue;
                     }
+                    let mut handle = stdout.lock();
+                    search_rows(state, &rows, false, Checks::default(), &mut |g| {
+                        match g {
+                            Ok(g) => emit_found(state, &g, enc, &mut handle),
+                            Err(r) => warn_skipped(r, enc, &mut handle),
+                        }
+                        true
+                    });
+                    wire::send(&mut handle, enc, &Out::Done { totalJ: 0 });
+                } else {
+                    wire::send(&mut stdout, enc, &Out::Error{ message: "not initialized".into()});
+                }
+            }
+            Msg::CompleteThreeRows { rows } => {
+                if let Some(ref state) = state_opt {
+                    let fourth = match fourth_rows(state, rows) {
+                        Ok(f) => f,
+                        Err(message) => { wire::send(&mut stdout, enc, &Out::Error{ message }); continue; }
+                    };
+                    let mut handle = stdout.lock();
+                    for x in fourth {
+                        let four = [rows[0], rows[1], rows[2], x];
+                        search_rows(state, &four, false, Checks::default(), &mut |g| {
+                            match g {
+                                Ok(g) => emit_found(state, &g, enc, &mut handle),
+                                Err(r) => warn_skipped(r, enc, &mut handle),
+                            }
+                            true
+                        });
+                    }
+                    wire::send(&mut handle, enc, &Out::Done { totalJ: 0 });
+                } else {
+                    wire::send(&mut stdout, enc, &Out::Error{ message: "not initialized".into()});
+                }
+            }
+            Msg::Ping => {
+                wire::send(&mut stdout, enc, &Out::Pong);
+            }
+            Msg::Cancel => {} // no WorkBatch running: nothing to stop
+            Msg::VerifyAdjacency => {
+                if let Some(ref state) = state_opt {
+                    match verify_adjacency(state) {
+                        Ok(mismatches) if mismatches.is_empty() => wire::send(&mut stdout, enc, &Out::Valid),
+                        Ok(mismatches) => wire::send(&mut stdout, enc, &Out::Error{ message: format!("adjacency does not match the masks: {}", mismatches.join("; ")) }),
+                        Err(message) => wire::send(&mut stdout, enc, &Out::Error{ message }),
+                    }
+                } else {
+                    wire::send(&mut stdout, enc, &Out::Error{ message: "not initialized".into()});
+                }
+            }
+            Msg::Categories => {
+                if let Some(ref state) = state_opt {
+                    wire::send(&mut stdout, enc, &Out::Categories{ categories: state.categories.clone() });
+                } else {
+                    wire::send(&mut stdout, enc, &Out::Error{ message: "not initialized".into()});
+                }
+            }
+            Msg::FindOne { seed } => {
+                if let Some(ref state) = state_opt {
+                    let mut handle = stdout.lock();
+                    if let Some(g) = find_one(state, seed, enc, &mut handle) { emit_found(state, &g, enc, &mut handle); }
+                    wire::send(&mut handle, enc, &Out::Done { totalJ: 0 });
+                } else {
+                    wire::send(&mut stdout, enc, &Out::Error{ message: "not initialized".into()});
                 }
-                state_opt = Some(State { masks, n1, n2, categories, meta_map, subset });
-                let _ = writeln!(stdout, "{}", serde_json::to_string(&Out::Ready).unwrap());
             }
-            Msg::Work { start, end, jStart, jEnd } => {
+            Msg::Sample { start, end, k, seed } => {
                 if let Some(ref state) = state_opt {
                     let mut handle = stdout.lock();
-                    run_work_streaming(state, start, end, jStart, jEnd, &mut handle);
+                    let (grids, total) = sample(state, start, end, k, seed, enc, &mut handle);
+                    for g in &grids { emit_found(state, g, enc, &mut handle); }
+                    wire::send(&mut handle, enc, &Out::Sampled { total });
                 } else {
-                    let _ = writeln!(stdout, "{}", serde_json::to_string(&Out::Error{ message: "not initialized".into()}).unwrap());
+                    wire::send(&mut stdout, enc, &Out::Error{ message: "not initialized".into()});
+                }
+            }
+            Msg::Work { start, end, jStart, jEnd, seed, shuffleJ, skipUniqueness, explain, checkpoint_path } => {
+                if let Some(ref state) = state_opt {
+                    let mut handle = stdout.lock();
+                    let work = WorkParams {
+                        start,
+                        end,
+                        j_start: jStart,
+                        j_end: jEnd,
+                        seed,
+                        shuffle_j: shuffleJ.unwrap_or(false),
+                        skip_uniqueness: skipUniqueness.unwrap_or(false),
+                        explain: explain.unwrap_or(false),
+                        checkpoint_path,
+                    };
+                    let mut tallies = Tallies::default();
+                    run_work_streaming(state, &work, enc, &mut handle, &mut tallies);
+                    tallies.send(state, enc, &mut handle);
+                } else {
+                    wire::send(&mut stdout, enc, &Out::Error{ message: "not initialized".into()});
+                }
+            }
+            Msg::WorkBatch { ranges } => {
+                if let Some(ref state) = state_opt {
+                    let mut handle = stdout.lock();
+                    // anything else that arrived meanwhile stays queued, in order, for the main loop
+                    let mut cancelled = || {
+                        pending.extend(inbox.try_iter());
+                        let at = pending.iter().position(|m| matches!(m, Ok(Msg::Cancel)));
+                        at.map(|p| pending.remove(p)).is_some()
+                    };
+                    run_batch(state, &ranges, enc, &mut handle, &mut cancelled);
+                } else {
+                    wire::send(&mut stdout, enc, &Out::Error{ message: "not initialized".into()});
                 }
             }
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // State over `masks` with n1/n2 built by the shared adjacency code and default options.
+    fn test_state(masks: Vec<Vec<u32>>) -> State {
+        let n = masks.len();
+        let (n1, n2) = build(&masks, false, 0);
+        let subset = subset_matrix(&masks, false);
+        State {
+            single: pack_single(&masks),
+            masks,
+            limb_bits: 32,
+            n1,
+            n2,
+            categories: (0..n).map(|i| format!("c{}", i)).collect(),
+            meta_map: vec![Vec::new(); n],
+            subset,
+            emit_words: false,
+            emit_cell_counts: false,
+            emit_distinct_words: false,
+            hardest_cells: 0,
+            emit_connectivity: false,
+            emit_puzzle_hash: false,
+            emit_difficulty: false,
+            meta_report: false,
+            cooccurrence: false,
+            word_weights: None,
+            word_counts: None,
+            max_share: 0,
+            unique_solution: false,
+            soft_meta: false,
+            distinct_meta: false,
+            meta_penalty: 1.0,
+            tick_every: 2,
+            rows_n: 4,
+            cols_n: 4,
+            profile: false,
+            compact_found: false,
+            max_mem_bytes: DEFAULT_MAX_MEM_BYTES,
+            size_range: (0, u32::MAX),
+            max_n2_degree: usize::MAX,
+            max_candidate_cols: usize::MAX,
+            allow_subset_edges: false,
+            min_shared_words: 0,
+            exclude_metas: Vec::new(),
+            excluded: vec![false; n],
+            pool: None,
+        }
+    }
+
+    // Categories 0..4 are rows and 4..8 columns of a grid whose cell (r, c) holds word 4r + c
+    // and nothing else, plus a spare word in row 0.
+    fn grid_masks() -> Vec<Vec<u32>> {
+        let mut masks = vec![vec![0u32]; 8];
+        for r in 0..4 {
+            for c in 0..4 {
+                masks[r][0] |= 1 << (4 * r + c);
+                masks[4 + c][0] |= 1 << (4 * r + c);
+            }
+        }
+        masks[0][0] |= 1 << 16;
+        masks
+    }
+
+    #[test]
+    fn found_order_is_independent_of_row_order() {
+        let state = test_state(grid_masks());
+        let mut via_ij = Vec::new();
+        search_ij(&state, 0, 1, Checks::default(), &mut |g| { via_ij.extend(g.ok()); true });
+        let mut via_rows = Vec::new();
+        search_rows(&state, &[3, 1, 0, 2], false, Checks::default(), &mut |g| { via_rows.extend(g.ok()); true });
+        assert_eq!(via_ij.len(), 1);
+        assert_eq!(via_rows.len(), 1);
+        let (a, b) = (&via_ij[0], &via_rows[0]);
+        assert_eq!((&a.rows, &a.cols), (&vec![0, 1, 2, 3], &vec![4, 5, 6, 7]));
+        assert_eq!((&a.rows, &a.cols, &a.words, &a.counts), (&b.rows, &b.cols, &b.words, &b.counts));
+        assert_eq!(a.words[2][1], 9);
+    }
+
+    #[test]
+    fn full_sweep_finds_each_grid_once() {
+        // without the spare word the grid is valid in both orientations
+        let mut masks = grid_masks();
+        masks[0][0] &= !(1 << 16);
+        let state = test_state(masks);
+        let mut found = Vec::new();
+        for i in 0..state.masks.len() {
+            for j in j_candidates(&state, i) {
+                search_ij(&state, i, j, Checks::default(), &mut |g| { found.extend(g.ok().map(|g| (g.rows, g.cols))); true });
+            }
+        }
+        assert_eq!(found, vec![(vec![0, 1, 2, 3], vec![4, 5, 6, 7])]);
+    }
+
+    #[test]
+    fn threaded_search_matches_serial() {
+        let mut state = test_state(grid_masks());
+        let sweep = |state: &State| {
+            let mut found = Vec::new();
+            for i in 0..state.masks.len() {
+                for j in j_candidates(state, i) {
+                    search_ij(state, i, j, Checks { skip_cells: false, explain: true }, &mut |g| { found.push(g.map(|g| (g.rows, g.cols)).map_err(|r| (r.cols, r.reason))); true });
+                }
+            }
+            found
+        };
+        let serial = sweep(&state);
+        state.pool = Some(rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap());
+        assert_eq!(sweep(&state), serial);
+    }
+
+    #[test]
+    fn failed_checkpoint_save_warns_once() {
+        let state = test_state(grid_masks());
+        let path = std::env::temp_dir().join("cdx_worker_no_such_dir").join("ckpt.json");
+        let work = WorkParams { start: 0, end: 8, j_start: None, j_end: None, seed: None, shuffle_j: false, skip_uniqueness: false, explain: false, checkpoint_path: Some(path.to_string_lossy().into_owned()) };
+        let mut out = Vec::new();
+        run_work_streaming(&state, &work, Encoding::Json, &mut out, &mut Tallies::default());
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches("\"type\":\"Warning\"").count(), 1);
+        assert!(out.contains("cannot save checkpoint") && out.contains("\"type\":\"Found\""));
+    }
 
+    #[test]
+    fn find_one_moves_past_a_skipped_row_set() {
+        // rows at 0, 1, 3, 4 and columns at 5..9 as in grid_masks; X (2) and the extras Y and Z
+        // (9, 10) give the row set {0, 1, 2, 3}, which (0, 1) reaches first, six candidates
+        let w = |words: &[u32]| vec![words.iter().fold(0u32, |m, &b| m | 1 << b)];
+        let mut masks = vec![w(&[0, 1, 2, 3]), w(&[4, 5, 6, 7]), w(&[0, 5, 10, 15, 20]), w(&[8, 9, 10, 11]), w(&[12, 13, 14, 15])];
+        masks.extend((0..4).map(|c| w(&[c, 4 + c, 8 + c, 12 + c])));
+        masks.push(w(&[1, 6, 9, 20, 21]));
+        masks.push(w(&[2, 7, 11, 20, 22]));
+        let mut state = test_state(masks);
+        state.max_candidate_cols = 5;
+        for seed in 0..8 {
+            let mut out = Vec::new();
+            let g = find_one(&state, Some(seed), Encoding::Json, &mut out).unwrap();
+            assert_eq!((g.rows, g.cols), (vec![0, 1, 3, 4], vec![5, 6, 7, 8]));
+            assert!(String::from_utf8(out).unwrap().contains("skipped rows [0, 1, 2, 3]: 6 candidate columns"));
+        }
+    }
+
+    #[test]
+    fn cancelled_batch_stops_at_a_range_boundary() {
+        let state = test_state(grid_masks());
+        let ranges = [(0, 1, None, None), (1, 8, None, None), (0, 8, None, None)];
+        let mut asked = 0;
+        let mut out = Vec::new();
+        run_batch(&state, &ranges, Encoding::Json, &mut out, &mut || { asked += 1; asked > 1 });
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches("\"type\":\"Done\"").count(), 1);
+        assert!(out.ends_with("{\"type\":\"BatchDone\",\"ranges\":1}\n"));
+        // range 0..1 holds the grid; it was searched once, before the Cancel
+        assert_eq!(out.matches("\"type\":\"Found\"").count(), 1);
+    }
+
+    #[test]
+    fn only_searched_row_sets_are_reported_as_skipped() {
+        // symmetric grid: rows {4, 5, 6, 7} would be dropped by the symmetry break anyway
+        let mut masks = grid_masks();
+        masks[0][0] &= !(1 << 16);
+        let mut state = test_state(masks);
+        state.max_candidate_cols = 3;
+        let mut skipped = Vec::new();
+        for i in 0..state.masks.len() {
+            for j in j_candidates(&state, i) {
+                search_ij(&state, i, j, Checks::default(), &mut |g| { skipped.extend(g.err().map(|r| r.rows)); true });
+            }
+        }
+        assert_eq!(skipped, vec![vec![0, 1, 2, 3]]);
+    }
+
+    #[test]
+    fn excl_needs_a_word_of_each_row_outside_the_others() {
+        let state = test_state(vec![vec![0b0011], vec![0b0110], vec![0b1100], vec![0b0010]]);
+        assert!(excl(&[0, 2], &state));
+        assert!(excl(&[0, 1], &state));
+        // row 1's words are split between rows 0 and 2
+        assert!(!excl(&[0, 1, 2], &state));
+        // row 3's only word is also in row 0
+        assert!(!excl(&[0, 3], &state));
+        assert!(!excl(&[1, 3], &state));
+    }
+
+    #[test]
+    fn single_limb_paths_match_the_general_ones() {
+        let mut rng = SplitMix64::new(7);
+        let mut masks: Vec<Vec<u32>> = (0..12).map(|_| { let x = rng.next_u64(); vec![x as u32, (x >> 32) as u32] }).collect();
+        masks.extend(grid_masks().into_iter().map(|m| vec![m[0], 0]));
+        let mut state = test_state(masks);
+        assert!(state.single.is_some());
+        state.emit_difficulty = true;
+        state.word_weights = Some((0..64).map(|w| w as f64).collect());
+        state.word_counts = Some((0..64).map(|w| w % 5).collect());
+        let summary = |r: Result<Grid, CellFailure>| r.map(|g| (g.words, g.counts, g.distinct, g.weakest_weight, g.rarity)).map_err(|f| (f.row, f.col, f.count));
+        let mut draws: Vec<(Vec<usize>, Vec<usize>)> = vec![((12..16).collect(), (16..20).collect())];
+        for _ in 0..200 {
+            let mut idx: Vec<usize> = (0..20).collect();
+            for a in 0..8 { let b = a + rng.below(20 - a); idx.swap(a, b); }
+            draws.push((idx[..4].to_vec(), idx[4..8].to_vec()));
+        }
+        for (max_share, unique_solution) in [(0, false), (0, true), (1, false), (2, false)] {
+            state.max_share = max_share;
+            state.unique_solution = unique_solution;
+            for (rows, cols) in &draws {
+                let single = state.single.take();
+                let (general_excl, general) = (excl(rows, &state), summary(check_cells(rows, cols, &state)));
+                state.single = single;
+                assert_eq!(excl(rows, &state), general_excl);
+                assert_eq!(summary(check_cells(rows, cols, &state)), general);
+            }
+        }
+    }
+
+    #[test]
+    fn rarity_averages_each_cells_rarest_word() {
+        let mut state = test_state(grid_masks());
+        let mut counts = vec![2; 17];
+        counts[5] = 1; // cell (1, 1)
+        state.word_counts = Some(counts);
+        let g = check_cells(&[0, 1, 2, 3], &[4, 5, 6, 7], &state).ok().unwrap();
+        assert_eq!(g.rarity, Some((15.0 * 0.5 + 1.0) / 16.0));
+    }
+
+    #[test]
+    fn verify_adjacency_reports_edited_lists() {
+        let mut state = test_state(grid_masks());
+        assert_eq!(verify_adjacency(&state), Ok(Vec::new()));
+        state.n2[0].retain(|&x| x != 1);
+        state.n1[2].push(9);
+        assert_eq!(verify_adjacency(&state).unwrap(), vec!["n1[2] missing [], extra [9]", "n2[0] missing [1], extra []"]);
+        state.max_mem_bytes = build_bytes(8) - 1;
+        assert!(verify_adjacency(&state).unwrap_err().starts_with("adjacency build for 8 categories needs"));
+    }
+
+    #[test]
+    fn excluded_metas_leave_every_pool() {
+        let mut state = test_state(grid_masks());
+        state.meta_map[1] = vec!["Time".into(), "Letter Patterns".into()];
+        state.meta_map[5] = vec!["Letter Patterns".into()];
+        state.exclude_metas = vec!["Letter Patterns".into()];
+        apply_exclusions(&mut state);
+        assert_eq!(state.excluded.iter().filter(|&&x| x).count(), 2);
+        assert_eq!(verify_adjacency(&state), Ok(Vec::new()));
+        assert!(j_candidates(&state, 1).is_empty());
+        assert!(!j_candidates(&state, 0).contains(&1));
+        let mut found = 0;
+        search_ij(&state, 0, 2, Checks::default(), &mut |g| { found += g.is_ok() as usize; true });
+        assert_eq!(found, 0);
+        assert_eq!(check_fixed_rows(&state, &[0, 1, 2, 3]).unwrap_err(), "row 1 is excluded");
+    }
+
+    #[test]
+    fn add_categories_keeps_edges_through_excluded_neighbours() {
+        let mut state = test_state(grid_masks());
+        state.meta_map[4] = vec!["Letter Patterns".into()];
+        state.exclude_metas = vec!["Letter Patterns".into()];
+        apply_exclusions(&mut state);
+        // row 1's words plus a new one: its n1 neighbours include excluded column 4
+        let mask = vec![(0xf0 | 1 << 20) as u64];
+        add_categories(&mut state, vec![mask], vec!["new".into()], vec![MetaTags::One(None)]).unwrap();
+        assert_eq!(verify_adjacency(&state), Ok(Vec::new()));
+        assert!(state.n2[0].contains(&8));
+    }
+
+    #[test]
+    fn add_categories_respects_max_mem_bytes() {
+        let mut state = test_state(grid_masks());
+        state.max_mem_bytes = BitMatrix::bytes_for(8);
+        let err = add_categories(&mut state, vec![vec![1 << 20]], vec!["new".into()], vec![MetaTags::One(None)]).unwrap_err();
+        assert!(err.starts_with("subset matrix for 9 categories needs"), "{}", err);
+        assert_eq!((state.masks.len(), state.categories.len(), state.subset.len()), (8, 8, 8));
+    }
+
+    #[test]
+    fn add_categories_keeps_compact_indices_within_u16() {
+        let mut state = test_state(grid_masks());
+        state.compact_found = true;
+        let extra = u16::MAX as usize + 1 - 8 + 1;
+        let err = add_categories(&mut state, vec![vec![1 << 20]; extra], vec!["new".into(); extra], vec![MetaTags::One(None); extra]).unwrap_err();
+        assert_eq!(err, "compactFound packs indices as u16, but AddCategories would bring the total to 65537 categories");
+        assert_eq!(state.masks.len(), 8);
+    }
+
+    #[test]
+    fn noop_update_mask_keeps_edges_through_excluded_neighbours() {
+        let mut state = test_state(grid_masks());
+        state.meta_map[4] = vec!["Letter Patterns".into()];
+        state.exclude_metas = vec!["Letter Patterns".into()];
+        apply_exclusions(&mut state);
+        let before = (state.n1.clone(), state.n2.clone());
+        // rows 0 and 1 share exactly four n1 neighbours, one of them excluded column 4
+        let mask = state.masks[0].iter().map(|&x| x as u64).collect();
+        update_mask(&mut state, 0, mask).unwrap();
+        assert_eq!(verify_adjacency(&state), Ok(Vec::new()));
+        assert_eq!((state.n1, state.n2), before);
+    }
+
+    #[test]
+    fn meta_counts_enforce_the_caps() {
+        let mut state = test_state(grid_masks());
+        state.meta_map = ["Colours", "Colours", "Colours", "Letter Patterns", "Letter Patterns", "Time", "Time", "Time"].iter().map(|m| vec![m.to_string()]).collect();
+        assert!(check_rows_meta(&[0, 1], &state));
+        assert!(!check_rows_meta(&[0, 1, 2], &state));
+        assert!(check_meta_constraint(&[0, 1, 3], &[5, 6], &state));
+        assert!(!check_meta_constraint(&[0, 1, 3], &[4, 5, 6], &state));
+        assert!(!check_meta_constraint(&[0, 1], &[5, 6, 7], &state));
+        state.soft_meta = true;
+        assert!(check_meta_constraint(&[0, 1, 2], &[4, 5, 6, 7], &state));
+        // one Colours and one Time over the cap of 2; a single Letter Patterns is within its cap
+        assert_eq!(meta_excess(&[0, 1, 2], &[4, 5, 6, 7], &state), 2);
+        // every tag of a multi-meta category counts toward its own cap
+        state.soft_meta = false;
+        state.meta_map[3] = vec!["Colours".into(), "Letter Patterns".into()];
+        assert!(!check_rows_meta(&[0, 1, 3], &state));
+        assert!(check_rows_meta(&[0, 3], &state));
+        assert!(!check_meta_constraint(&[0, 3], &[4], &state));
+    }
+
+    #[test]
+    fn known_good_grid_passes_check_cells() {
+        let state = test_state(grid_masks());
+        let g = check_cells(&[0, 1, 2, 3], &[4, 5, 6, 7], &state).ok().unwrap();
+        assert_eq!(g.words, (0..4).map(|r| (0..4).map(|c| 4 * r + c).collect()).collect::<CellGrid>());
+        assert_eq!(g.counts, vec![vec![1; 4]; 4]);
+        assert_eq!(g.distinct, 16);
+        // a column that repeats another's words leaves its cells without a unique word
+        let mut masks = grid_masks();
+        masks.push(masks[4].clone());
+        let state = test_state(masks);
+        let fail = check_cells(&[0, 1, 2, 3], &[4, 5, 6, 8], &state).err().unwrap();
+        assert_eq!((fail.row, fail.col, fail.count), (0, 4, 0));
+    }
+
+    #[test]
+    fn malformed_init_lengths_are_reported() {
+        let payload = r#"{"type":"Init","masks":[[3],[6],[12]],"n1":[[1],[0,2],[1]],"n2":[[],[],[]],"categories":["a","b","c"],"meta_map":[null,"M"]}"#;
+        let Ok(Msg::Init { masks, n1, n2, categories, meta_map, .. }) = serde_json::from_str::<Msg>(payload) else { panic!("payload should parse as Init") };
+        let masks = repack_limbs(masks, 32).unwrap();
+        let meta_map: Vec<Vec<String>> = meta_map.into_iter().map(Vec::from).collect();
+        assert_eq!(check_init_lengths(&masks, &n1, &n2, &categories, &meta_map).unwrap_err(), "meta_map has 2 entries but there are 3 masks");
+        let meta_map = vec![Vec::new(); 3];
+        assert!(check_init_lengths(&masks, &n1, &n2, &categories, &meta_map).is_ok());
+        assert_eq!(check_init_lengths(&masks, &[vec![3], vec![], vec![]], &n2, &categories, &meta_map).unwrap_err(), "n1[0] lists category 3, but there are only 3");
+        assert_eq!(check_init_lengths(&[vec![1], vec![1, 0], vec![1]], &n1, &n2, &categories, &meta_map).unwrap_err(), "masks[1] has 2 limbs but masks[0] has 1");
+    }
+}
@@ -0,0 +1,191 @@
+//! Bitset predicates over category masks (32-bit limbs) and a packed n×n boolean matrix.
+
+use rayon::prelude::*;
+
+pub fn intersects(a: &[u32], b: &[u32]) -> bool {
+    a.iter().zip(b.iter()).any(|(x, y)| (x & y) != 0)
+}
+
+/// Number of bits (words) set in both masks.
+pub fn shared_words(a: &[u32], b: &[u32]) -> u32 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x & y).count_ones()).sum()
+}
+
+/// True when every bit of `a` is also set in `b`.
+pub fn subset(a: &[u32], b: &[u32]) -> bool {
+    a.iter().zip(b.iter()).all(|(x, y)| (x & !y) == 0)
+}
+
+/// Converts masks sent with `limb_bits`-wide limbs (32 or 64) to the canonical 32-bit limbs,
+/// splitting each 64-bit limb into its low then high half.
+pub fn repack_limbs(masks: Vec<Vec<u64>>, limb_bits: u32) -> Result<Vec<Vec<u32>>, String> {
+    match limb_bits {
+        32 => masks.into_iter().map(|m| m.into_iter().map(|x| u32::try_from(x).map_err(|_| format!("mask limb {} does not fit in 32 bits (limb_bits is 32)", x))).collect()).collect(),
+        64 => Ok(masks.into_iter().map(|m| m.into_iter().flat_map(|x| [x as u32, (x >> 32) as u32]).collect()).collect()),
+        _ => Err(format!("limb_bits must be 32 or 64, got {}", limb_bits)),
+    }
+}
+
+/// Each mask as a single u64 (first limb in the low half) when every mask has at most two
+/// 32-bit limbs, so small word lists can skip the per-limb loops; None otherwise.
+pub fn pack_single(masks: &[Vec<u32>]) -> Option<Vec<u64>> {
+    masks.iter().map(|m| match *m.as_slice() {
+        [] => Some(0),
+        [lo] => Some(lo as u64),
+        [lo, hi] => Some(lo as u64 | (hi as u64) << 32),
+        _ => None,
+    }).collect()
+}
+
+/// Square boolean matrix packed one bit per entry, each row padded to whole u64 words.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BitMatrix {
+    n: usize,
+    words_per_row: usize,
+    bits: Vec<u64>,
+}
+
+impl BitMatrix {
+    pub fn new(n: usize) -> Self {
+        let words_per_row = n.div_ceil(64);
+        BitMatrix { n, words_per_row, bits: vec![0; n * words_per_row] }
+    }
+
+    /// Bytes a packed `n` × `n` matrix needs, computed without allocating it.
+    pub fn bytes_for(n: usize) -> u64 {
+        n as u64 * n.div_ceil(64) as u64 * 8
+    }
+
+    pub fn len(&self) -> usize { self.n }
+
+    pub fn is_empty(&self) -> bool { self.n == 0 }
+
+    /// Copy of this matrix enlarged to `n` × `n`; the new entries start out false.
+    pub fn grown(&self, n: usize) -> BitMatrix {
+        let mut m = BitMatrix::new(n.max(self.n));
+        for i in 0..self.n {
+            let src = &self.bits[i * self.words_per_row..(i + 1) * self.words_per_row];
+            m.bits[i * m.words_per_row..i * m.words_per_row + self.words_per_row].copy_from_slice(src);
+        }
+        m
+    }
+
+    #[inline]
+    pub fn get(&self, i: usize, j: usize) -> bool {
+        (self.bits[i * self.words_per_row + j / 64] >> (j % 64)) & 1 == 1
+    }
+
+    #[inline]
+    pub fn set(&mut self, i: usize, j: usize, v: bool) {
+        let w = &mut self.bits[i * self.words_per_row + j / 64];
+        if v { *w |= 1 << (j % 64); } else { *w &= !(1 << (j % 64)); }
+    }
+}
+
+// (i, j) entry of the subset relation: directional i ⊆ j, or either direction when symmetric.
+fn subset_entry(masks: &[Vec<u32>], i: usize, j: usize, symmetric: bool) -> bool {
+    i != j && (subset(&masks[i], &masks[j]) || (symmetric && subset(&masks[j], &masks[i])))
+}
+
+/// Single-threaded reference implementation of [`subset_matrix`].
+pub fn subset_matrix_serial(masks: &[Vec<u32>], symmetric: bool) -> BitMatrix {
+    let n = masks.len();
+    let mut m = BitMatrix::new(n);
+    for i in 0..n {
+        for j in 0..n {
+            if subset_entry(masks, i, j, symmetric) { m.set(i, j, true); }
+        }
+    }
+    m
+}
+
+/// Subset matrix computed in parallel by rows. With `symmetric`, (i, j) is set when
+/// either mask contains the other; otherwise only when masks[i] ⊆ masks[j].
+pub fn subset_matrix(masks: &[Vec<u32>], symmetric: bool) -> BitMatrix {
+    let mut m = BitMatrix::new(masks.len());
+    let wpr = m.words_per_row;
+    if wpr == 0 { return m; }
+    m.bits.par_chunks_mut(wpr).enumerate().for_each(|(i, row)| {
+        for j in 0..masks.len() {
+            if subset_entry(masks, i, j, symmetric) { row[j / 64] |= 1 << (j % 64); }
+        }
+    });
+    m
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Small deterministic masks with plenty of subset relations (including equal masks).
+    fn sample_masks(n: usize, limbs: usize) -> Vec<Vec<u32>> {
+        let mut x: u64 = 0x9e3779b97f4a7c15;
+        let mut masks = Vec::with_capacity(n);
+        for i in 0..n {
+            if i % 5 == 4 {
+                let prev: &Vec<u32> = &masks[i - 1];
+                masks.push(prev.iter().map(|&w| w & 0x0f0f_0f0f).collect());
+                continue;
+            }
+            let mut m = Vec::with_capacity(limbs);
+            for _ in 0..limbs {
+                x ^= x << 13; x ^= x >> 7; x ^= x << 17;
+                m.push((x as u32) & (x >> 32) as u32);
+            }
+            masks.push(m);
+        }
+        masks
+    }
+
+    #[test]
+    fn intersects_finds_a_shared_bit_in_any_limb() {
+        assert!(intersects(&[0b0110, 0], &[0b0100, 0]));
+        assert!(intersects(&[1, 0, 1 << 31], &[0, 0, 1 << 31]));
+        assert!(!intersects(&[0b0011, 8], &[0b1100, 4]));
+        assert!(!intersects(&[0, 0], &[0, 0]));
+    }
+
+    #[test]
+    fn subset_needs_every_bit() {
+        assert!(subset(&[0b0101, 2], &[0b0111, 3]));
+        assert!(subset(&[0b0101, 2], &[0b0101, 2]));
+        assert!(subset(&[0, 0], &[0b1, 0]));
+        assert!(!subset(&[0b0101, 2], &[0b0111, 1]));
+        assert!(!subset(&[0b1000, 0], &[0b0111, 0]));
+    }
+
+    #[test]
+    fn repack_limbs_widths() {
+        assert_eq!(repack_limbs(vec![vec![0x0000_0002_0000_0001, 7]], 64).unwrap(), vec![vec![1, 2, 7, 0]]);
+        assert_eq!(repack_limbs(vec![vec![5, 6]], 32).unwrap(), vec![vec![5, 6]]);
+        assert!(repack_limbs(vec![vec![1 << 32]], 32).is_err());
+        assert!(repack_limbs(vec![vec![1]], 16).is_err());
+    }
+
+    #[test]
+    fn pack_single_only_fits_two_limbs() {
+        assert_eq!(pack_single(&[vec![5], vec![1 << 31]]), Some(vec![5, 1 << 31]));
+        assert_eq!(pack_single(&[vec![1, 2]]), Some(vec![0x0000_0002_0000_0001]));
+        assert_eq!(pack_single(&[vec![1], vec![1, 2, 3]]), None);
+    }
+
+    #[test]
+    fn parallel_matches_serial() {
+        for &(n, limbs) in &[(0, 1), (1, 1), (63, 1), (64, 2), (130, 3)] {
+            let masks = sample_masks(n, limbs);
+            for symmetric in [false, true] {
+                assert_eq!(subset_matrix(&masks, symmetric), subset_matrix_serial(&masks, symmetric));
+            }
+        }
+    }
+
+    #[test]
+    fn symmetric_entries_agree() {
+        let masks = sample_masks(100, 2);
+        let m = subset_matrix(&masks, true);
+        for i in 0..100 {
+            assert!(!m.get(i, i));
+            for j in 0..100 { assert_eq!(m.get(i, j), m.get(j, i)); }
+        }
+    }
+}
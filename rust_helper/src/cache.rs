@@ -0,0 +1,200 @@
+//! On-disk caches, so repeated runs over an unchanged word list can skip recomputing
+//! (or re-piping) the adjacency.
+//!
+//! Both formats share a header (integers LE): a 4-byte magic, a version byte, and the word
+//! list hash as a u16 length plus UTF-8 bytes. Lists of indices are a u32 length followed
+//! by u32 entries; strings are a u32 length followed by UTF-8 bytes.
+//!
+//! - adjacency (`CDXA`): category count as u32, then n1 and n2 as one list per category.
+//! - bundle (`CDXB`): category count and mask limbs as u32, the masks, n1, n2, the category
+//!   names, then one meta list per category (a u32 count, then that many strings).
+
+use std::path::Path;
+
+const ADJ_MAGIC: &[u8; 4] = b"CDXA";
+const ADJ_VERSION: u8 = 1;
+const BUNDLE_MAGIC: &[u8; 4] = b"CDXB";
+const BUNDLE_VERSION: u8 = 2; // 2: a category may have several metas
+
+pub type Adjacency = Vec<Vec<usize>>;
+
+/// Everything a worker needs for Init, as written by cdx_helper's `cache_out`.
+#[derive(Debug, PartialEq)]
+pub struct Bundle {
+    pub word_list_hash: String,
+    pub masks: Vec<Vec<u32>>,
+    pub n1: Adjacency,
+    pub n2: Adjacency,
+    pub categories: Vec<String>,
+    pub meta_map: Vec<Vec<String>>, // every meta of each category (empty for none)
+}
+
+fn put_u32(out: &mut Vec<u8>, x: usize) {
+    out.extend_from_slice(&(x as u32).to_le_bytes());
+}
+
+fn put_header(out: &mut Vec<u8>, magic: &[u8; 4], version: u8, word_list_hash: &str) {
+    out.extend_from_slice(magic);
+    out.push(version);
+    out.extend_from_slice(&(word_list_hash.len() as u16).to_le_bytes());
+    out.extend_from_slice(word_list_hash.as_bytes());
+}
+
+fn put_lists(out: &mut Vec<u8>, lists: &[Vec<usize>]) {
+    for l in lists {
+        put_u32(out, l.len());
+        for &x in l { put_u32(out, x); }
+    }
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_u32(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Bounds-checked little-endian reader over a loaded cache file.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(n).filter(|&e| e <= self.bytes.len()).ok_or("cache file is truncated")?;
+        let s = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(s)
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| "cache file has a non-UTF-8 string".to_string())
+    }
+
+    // Checks magic and version and returns the stored word list hash.
+    fn header(&mut self, magic: &[u8; 4], version: u8, what: &str) -> Result<String, String> {
+        if self.take(4)? != magic { return Err(format!("not {} cache file", what)); }
+        let v = self.take(1)?[0];
+        if v != version { return Err(format!("{} cache version {} not supported (expected {})", what, v, version)); }
+        let hlen = self.take(2)?;
+        let hlen = u16::from_le_bytes([hlen[0], hlen[1]]) as usize;
+        Ok(String::from_utf8_lossy(self.take(hlen)?).into_owned())
+    }
+
+    fn lists(&mut self, n: usize) -> Result<Adjacency, String> {
+        let mut lists = Vec::with_capacity(n);
+        for _ in 0..n {
+            let len = self.u32()?;
+            let l = (0..len).map(|_| self.u32()).collect::<Result<Vec<usize>, String>>()?;
+            if let Some(&x) = l.iter().find(|&&x| x >= n) { return Err(format!("cache index {} out of range", x)); }
+            lists.push(l);
+        }
+        Ok(lists)
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("cannot read cache {}: {}", path.display(), e))
+}
+
+/// Writes n1/n2 tagged with the hash of the word list they were computed from.
+pub fn write_adjacency<P: AsRef<Path>>(path: P, word_list_hash: &str, n1: &[Vec<usize>], n2: &[Vec<usize>]) -> std::io::Result<()> {
+    let mut out = Vec::new();
+    put_header(&mut out, ADJ_MAGIC, ADJ_VERSION, word_list_hash);
+    put_u32(&mut out, n1.len());
+    put_lists(&mut out, n1);
+    put_lists(&mut out, n2);
+    std::fs::write(path, out)
+}
+
+/// Loads (n1, n2), failing if the file was written for a different word list.
+pub fn read_adjacency<P: AsRef<Path>>(path: P, word_list_hash: &str) -> Result<(Adjacency, Adjacency), String> {
+    let bytes = read_file(path.as_ref())?;
+    let mut cur = Cursor { bytes: &bytes, pos: 0 };
+    let stored = cur.header(ADJ_MAGIC, ADJ_VERSION, "an adjacency")?;
+    if stored != word_list_hash {
+        return Err(format!("adjacency cache is stale: built for word_list_hash {} but Init has {}", stored, word_list_hash));
+    }
+    let n = cur.u32()?;
+    let n1 = cur.lists(n)?;
+    let n2 = cur.lists(n)?;
+    Ok((n1, n2))
+}
+
+pub fn write_bundle<P: AsRef<Path>>(path: P, b: &Bundle) -> std::io::Result<()> {
+    let mut out = Vec::new();
+    put_header(&mut out, BUNDLE_MAGIC, BUNDLE_VERSION, &b.word_list_hash);
+    put_u32(&mut out, b.masks.len());
+    put_u32(&mut out, b.masks.first().map_or(0, |m| m.len()));
+    for m in &b.masks {
+        for &x in m { out.extend_from_slice(&x.to_le_bytes()); }
+    }
+    put_lists(&mut out, &b.n1);
+    put_lists(&mut out, &b.n2);
+    for c in &b.categories { put_str(&mut out, c); }
+    for m in &b.meta_map {
+        put_u32(&mut out, m.len());
+        for s in m { put_str(&mut out, s); }
+    }
+    std::fs::write(path, out)
+}
+
+pub fn read_bundle<P: AsRef<Path>>(path: P) -> Result<Bundle, String> {
+    let bytes = read_file(path.as_ref())?;
+    let mut cur = Cursor { bytes: &bytes, pos: 0 };
+    let word_list_hash = cur.header(BUNDLE_MAGIC, BUNDLE_VERSION, "a bundle")?;
+    let n = cur.u32()?;
+    let limbs = cur.u32()?;
+    let masks = (0..n).map(|_| (0..limbs).map(|_| cur.u32().map(|x| x as u32)).collect()).collect::<Result<Vec<Vec<u32>>, String>>()?;
+    let n1 = cur.lists(n)?;
+    let n2 = cur.lists(n)?;
+    let categories = (0..n).map(|_| cur.string()).collect::<Result<Vec<String>, String>>()?;
+    let mut meta_map = Vec::with_capacity(n);
+    for _ in 0..n {
+        let len = cur.u32()?;
+        meta_map.push((0..len).map(|_| cur.string()).collect::<Result<Vec<String>, String>>()?);
+    }
+    Ok(Bundle { word_list_hash, masks, n1, n2, categories, meta_map })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("cdx_{}_{}.bin", name, std::process::id()))
+    }
+
+    #[test]
+    fn round_trip_and_stale_hash() {
+        let path = temp_path("adj");
+        let n1 = vec![vec![1, 2], vec![0], vec![0]];
+        let n2 = vec![vec![], vec![2], vec![1]];
+        write_adjacency(&path, "abc", &n1, &n2).unwrap();
+        assert_eq!(read_adjacency(&path, "abc").unwrap(), (n1, n2));
+        assert!(read_adjacency(&path, "def").unwrap_err().contains("stale"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bundle_round_trip() {
+        let path = temp_path("bundle");
+        let b = Bundle {
+            word_list_hash: "abc".into(),
+            masks: vec![vec![1, 0], vec![3, 8], vec![4, 0]],
+            n1: vec![vec![1], vec![0, 2], vec![1]],
+            n2: vec![vec![], vec![], vec![]],
+            categories: vec!["Birds".into(), "Colors".into(), "Greens".into()],
+            meta_map: vec![vec![], vec!["Nature".into()], vec!["Nature".into(), "Colours".into()]],
+        };
+        write_bundle(&path, &b).unwrap();
+        assert_eq!(read_bundle(&path).unwrap(), b);
+        assert!(read_adjacency(&path, "abc").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
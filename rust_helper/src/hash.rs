@@ -0,0 +1,32 @@
+//! SHA-256 fingerprints of category data, as lowercase hex.
+
+use sha2::{Digest, Sha256};
+
+/// The `wordListHash` solve_and_curate.js sends: the hash of words.json's raw bytes, which
+/// also fix the word order the masks' bits follow.
+pub fn word_list_hash(words_json: &[u8]) -> String {
+    hex::encode(Sha256::digest(words_json))
+}
+
+/// The puzzle's `puzzle_hash`, as `computePuzzleHash` in solve_and_curate.js: the row names
+/// joined with `|`, directly followed by the column names joined with `|`.
+pub fn puzzle_hash<S: AsRef<str>>(rows: &[S], cols: &[S]) -> String {
+    let join = |names: &[S]| names.iter().map(|n| n.as_ref()).collect::<Vec<&str>>().join("|");
+    hex::encode(Sha256::digest(format!("{}{}", join(rows), join(cols)).as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn word_list_hash_matches_js() {
+        let words = b"{\"apple\":[\"Fruits\",\"Red Things\"],\"lime\":[\"Fruits\"]}\n";
+        assert_eq!(word_list_hash(words), "3cf040eddcfe44196a24836042fe2603d81d264d559fffaae82a7e878f5b3418");
+    }
+
+    #[test]
+    fn puzzle_hash_matches_js() {
+        assert_eq!(puzzle_hash(&["Birds", "Colors"], &["Fruits", "Tools"]), "c323802031a8e66e284e2474627b429b71286081dfcf0ab538c880ab68fe4a6b");
+    }
+}
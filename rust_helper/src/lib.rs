@@ -0,0 +1,9 @@
+//! Shared helpers for the cdx_helper binaries.
+
+pub mod adjacency;
+pub mod bitset;
+pub mod cache;
+pub mod hash;
+pub mod meta;
+pub mod rng;
+pub mod wire;
@@ -1,88 +1,123 @@
+use cdx_helper::adjacency::{build, build_each, patch_changed};
+use cdx_helper::bitset::repack_limbs;
+use cdx_helper::cache::{write_adjacency, write_bundle, Bundle};
+use cdx_helper::meta::MetaTags;
+use cdx_helper::wire::{self, Encoding};
 use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
+use std::io::Read;
 
 #[derive(Deserialize)]
+#[allow(non_snake_case)]
 struct Input {
     // masks: Vec<Uint32Array> serialized as Vec<Vec<u32>>
-    masks: Vec<Vec<u32>>, // each is a bitset in 32-bit limbs
+    masks: Vec<Vec<u64>>, // each is a bitset in limb_bits-wide limbs
+    limb_bits: Option<u32>, // 32 (default) or 64; masks are repacked to 32-bit limbs
+    adjacency_out: Option<String>, // also write N1/N2 to this cache file for cdx_worker
+    word_list_hash: Option<String>, // recorded in the cache header (required with adjacency_out or cache_out)
+    cache_out: Option<String>, // write a bundle (masks, N1/N2, categories, meta_map) for cdx_worker's cache_path
+    #[serde(default)]
+    categories: Vec<String>, // only needed for cache_out
+    #[serde(default)]
+    meta_map: Vec<MetaTags>, // only for cache_out; null, a meta or a list of metas each; empty means no metas
+    changed: Option<Vec<usize>>, // patch mode: indices whose masks changed since n1/n2 were built
+    n1: Option<Vec<Vec<usize>>>, // patch mode: the previous N1
+    n2: Option<Vec<Vec<usize>>>, // patch mode: the previous N2
+    allowSubsetEdges: Option<bool>, // keep edges between subset-related categories (default false)
+    minSharedWords: Option<u32>, // n2 pairs must also have this many words in common (default 0 = off)
+    stream: Option<bool>, // write one {i, n1, n2} message per category as computed instead of N1/N2
 }
 
+// One category's adjacency in stream mode.
 #[derive(Serialize)]
+struct Row<'a> {
+    i: usize,
+    n1: &'a [usize],
+    n2: Vec<usize>,
+}
+
+#[derive(Serialize)]
+#[allow(non_snake_case)]
 struct Output {
     N1: Vec<Vec<usize>>,
     N2: Vec<Vec<usize>>,
 }
 
-fn intersects(a: &[u32], b: &[u32]) -> bool {
-    a.iter().zip(b.iter()).any(|(x, y)| (x & y) != 0)
+// Reads all of stdin, gunzipping it when asked to with --gzip or CDX_GZIP=1. JSON input is
+// also sniffed for the gzip magic bytes (no JSON text starts with them); a MessagePack frame
+// starts with its length, which can, so it needs the explicit flag.
+fn read_input(enc: Encoding) -> Vec<u8> {
+    let mut raw = Vec::new();
+    std::io::stdin().read_to_end(&mut raw).unwrap();
+    let flag = std::env::args().skip(1).any(|a| a == "--gzip");
+    let var = std::env::var("CDX_GZIP").map(|v| v == "1").unwrap_or(false);
+    let sniffed = enc == Encoding::Json && raw.starts_with(&[0x1f, 0x8b]);
+    if flag || var || sniffed {
+        let mut buf = Vec::new();
+        flate2::read::GzDecoder::new(raw.as_slice()).read_to_end(&mut buf).unwrap();
+        buf
+    } else {
+        raw
+    }
 }
 
 fn main() {
-    // Read stdin
-    let mut buf = String::new();
-    std::io::stdin().read_to_string(&mut buf).unwrap();
-    let inp: Input = serde_json::from_str(&buf).unwrap();
+    // Read stdin (plain or gzip-compressed; JSON or a single MessagePack frame)
+    let enc = Encoding::from_env();
+    let buf = read_input(enc);
+    let inp: Input = wire::decode(enc, wire::unframe(enc, &buf)).unwrap();
 
     let n = inp.masks.len();
-    let masks: Vec<Vec<u32>> = inp.masks;
-    let mask_slices: Vec<&[u32]> = masks.iter().map(|v| v.as_slice()).collect();
-
-    // S(i,j) is subset relation; we only need it to exclude relations in N1/N2 like in JS
-    // Compute subset matrix
-    let mut subset = vec![vec![false; n]; n];
-    for i in 0..n {
-        for j in (i + 1)..n {
-            let a_sub_b = mask_slices[i]
-                .iter()
-                .zip(mask_slices[j])
-                .all(|(a, b)| (a & !b) == 0);
-            let b_sub_a = mask_slices[j]
-                .iter()
-                .zip(mask_slices[i])
-                .all(|(b, a)| (b & !a) == 0);
-            if a_sub_b || b_sub_a {
-                subset[i][j] = true;
-                subset[j][i] = true;
-            }
-        }
+    let masks: Vec<Vec<u32>> = repack_limbs(inp.masks, inp.limb_bits.unwrap_or(32)).unwrap();
+    let allow_subset = inp.allowSubsetEdges.unwrap_or(false);
+    let min_shared = inp.minSharedWords.unwrap_or(0);
+    if inp.stream.unwrap_or(false) {
+        assert!(inp.changed.is_none() && inp.adjacency_out.is_none() && inp.cache_out.is_none(), "stream only supports a plain full build");
+        let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+        build_each(&masks, allow_subset, min_shared, |i, n1, n2| wire::write_msg(&mut out, enc, &Row { i, n1, n2 }).unwrap());
+        return;
     }
-
-    // Build A (1-away) and collect N1 sets
-    let mut A: Vec<Vec<u8>> = vec![vec![0; n]; n];
-    let mut n1: Vec<Vec<usize>> = vec![Vec::new(); n];
-    for i in 0..n {
-        for j in (i + 1)..n {
-            if !subset[i][j] && intersects(mask_slices[i], mask_slices[j]) {
-                A[i][j] = 1;
-                A[j][i] = 1;
-                n1[i].push(j);
-                n1[j].push(i);
-            }
+    let (n1, n2) = match inp.changed {
+        Some(changed) => {
+            let mut n1 = inp.n1.expect("changed requires n1");
+            let mut n2 = inp.n2.expect("changed requires n2");
+            assert!(n1.len() == n && n2.len() == n, "n1/n2 must have one list per mask");
+            assert!(changed.iter().all(|&c| c < n), "changed index out of range");
+            patch_changed(&masks, &mut n1, &mut n2, &changed, allow_subset, min_shared);
+            (n1, n2)
         }
+        None => build(&masks, allow_subset, min_shared),
+    };
+
+    if let Some(path) = &inp.adjacency_out {
+        let hash = inp.word_list_hash.as_deref().expect("adjacency_out requires word_list_hash");
+        write_adjacency(path, hash, &n1, &n2).unwrap();
     }
 
-    // Compute A2 = A * A (boolean count)
-    // and build B with threshold >= 4 (and not subset)
-    let mut n2: Vec<Vec<usize>> = vec![Vec::new(); n];
-    for i in 0..n {
-        for j in (i + 1)..n {
-            if subset[i][j] { continue; }
-            let mut count = 0u32;
-            // Count k where A[i][k] == 1 and A[k][j] == 1
-            for k in 0..n {
-                if A[i][k] == 1 && A[k][j] == 1 { count += 1; }
-            }
-            if count >= 4 {
-                n2[i].push(j);
-                n2[j].push(i);
-            }
-        }
+    if let Some(path) = &inp.cache_out {
+        let hash = inp.word_list_hash.clone().expect("cache_out requires word_list_hash");
+        assert_eq!(inp.categories.len(), n, "cache_out requires one category name per mask");
+        let meta_map: Vec<Vec<String>> = if inp.meta_map.is_empty() { vec![Vec::new(); n] } else { inp.meta_map.iter().cloned().map(Vec::from).collect() };
+        assert_eq!(meta_map.len(), n, "meta_map must have one entry per mask");
+        let bundle = Bundle { word_list_hash: hash, masks, n1, n2, categories: inp.categories.clone(), meta_map };
+        write_bundle(path, &bundle).unwrap();
+        let out = Output { N1: bundle.n1, N2: bundle.n2 };
+        write_output(enc, &out);
+        return;
     }
 
     let out = Output { N1: n1, N2: n2 };
+    write_output(enc, &out);
+}
+
+fn write_output(enc: Encoding, out: &Output) {
     let mut stdout = std::io::stdout();
-    let s = serde_json::to_string(&out).unwrap();
-    stdout.write_all(s.as_bytes()).unwrap();
+    match enc {
+        Encoding::Json => {
+            use std::io::Write;
+            stdout.write_all(serde_json::to_string(out).unwrap().as_bytes()).unwrap();
+        }
+        Encoding::MsgPack => wire::write_msg(&mut stdout, enc, out).unwrap(),
+    }
 }
 
 
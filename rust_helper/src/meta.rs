@@ -0,0 +1,32 @@
+//! Meta-category tags. A category may belong to any number of metas; on the wire each
+//! `meta_map` entry is `null`, a single meta name, or a list of names.
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum MetaTags {
+    One(Option<String>),
+    Many(Vec<String>),
+}
+
+impl From<MetaTags> for Vec<String> {
+    fn from(t: MetaTags) -> Self {
+        match t {
+            MetaTags::One(m) => m.into_iter().collect(),
+            MetaTags::Many(v) => v,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_and_multiple_tags_parse() {
+        let tags: Vec<MetaTags> = serde_json::from_str(r#"[null, "Colours", ["Colours", "Time"]]"#).unwrap();
+        let tags: Vec<Vec<String>> = tags.into_iter().map(Vec::from).collect();
+        assert_eq!(tags, vec![vec![], vec!["Colours".to_string()], vec!["Colours".to_string(), "Time".to_string()]]);
+    }
+}
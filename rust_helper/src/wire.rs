@@ -0,0 +1,107 @@
+//! Message framing shared by all binaries.
+//!
+//! JSON lines are the default. Passing `--msgpack` (or setting `CDX_ENCODING=msgpack`)
+//! switches a process to MessagePack frames, each prefixed with its length as a u32 LE.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{BufRead, Write};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Json,
+    MsgPack,
+}
+
+impl Encoding {
+    pub fn from_env() -> Self {
+        let flag = std::env::args().skip(1).any(|a| a == "--msgpack");
+        let var = std::env::var("CDX_ENCODING").map(|v| v.eq_ignore_ascii_case("msgpack")).unwrap_or(false);
+        if flag || var { Encoding::MsgPack } else { Encoding::Json }
+    }
+}
+
+/// Serializes one message (a JSON line or a length-prefixed MessagePack frame).
+pub fn write_msg<W: Write, T: Serialize>(w: &mut W, enc: Encoding, msg: &T) -> std::io::Result<()> {
+    match enc {
+        Encoding::Json => writeln!(w, "{}", serde_json::to_string(msg).unwrap()),
+        Encoding::MsgPack => {
+            let buf = rmp_serde::to_vec_named(msg).unwrap();
+            w.write_all(&(buf.len() as u32).to_le_bytes())?;
+            w.write_all(&buf)?;
+            w.flush()
+        }
+    }
+}
+
+/// True for the write errors that mean the reading end of the pipe has gone away.
+pub fn is_closed(e: &std::io::Error) -> bool {
+    matches!(e.kind(), std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::WriteZero)
+}
+
+/// `write_msg` for the long-lived binaries: once the parent has closed our stdout there is
+/// nobody left to answer, so exit quietly instead of looping on stdin. Other errors are
+/// ignored as before.
+pub fn send<W: Write, T: Serialize>(w: &mut W, enc: Encoding, msg: &T) {
+    if let Err(e) = write_msg(w, enc, msg) {
+        if is_closed(&e) { std::process::exit(0); }
+    }
+}
+
+/// Decodes a single payload (without the frame length prefix).
+pub fn decode<T: DeserializeOwned>(enc: Encoding, bytes: &[u8]) -> Result<T, String> {
+    match enc {
+        Encoding::Json => serde_json::from_slice(bytes).map_err(|e| format!("bad json: {}", e)),
+        Encoding::MsgPack => rmp_serde::from_slice(bytes).map_err(|e| format!("bad msgpack: {}", e)),
+    }
+}
+
+/// Reads the next message from `r`, reusing `buf`. Returns `None` at EOF.
+pub fn read_msg<R: BufRead, T: DeserializeOwned>(r: &mut R, enc: Encoding, buf: &mut Vec<u8>) -> Option<Result<T, String>> {
+    buf.clear();
+    match enc {
+        Encoding::Json => {
+            let n = r.read_until(b'\n', buf).unwrap();
+            if n == 0 { return None; }
+        }
+        Encoding::MsgPack => {
+            let mut len = [0u8; 4];
+            if r.read_exact(&mut len).is_err() { return None; }
+            buf.resize(u32::from_le_bytes(len) as usize, 0);
+            if r.read_exact(buf).is_err() { return None; }
+        }
+    }
+    Some(decode(enc, buf))
+}
+
+/// Strips the length prefix from a single MessagePack frame holding a whole input.
+pub fn unframe(enc: Encoding, bytes: &[u8]) -> &[u8] {
+    match enc {
+        Encoding::Json => bytes,
+        Encoding::MsgPack if bytes.len() >= 4 => {
+            let len = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+            &bytes[4..(4 + len).min(bytes.len())]
+        }
+        Encoding::MsgPack => bytes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A stdout whose reader has exited.
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> { Err(std::io::ErrorKind::BrokenPipe.into()) }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn closed_reader_is_detected() {
+        for enc in [Encoding::Json, Encoding::MsgPack] {
+            assert!(is_closed(&write_msg(&mut ClosedPipe, enc, &"x").unwrap_err()));
+        }
+    }
+}